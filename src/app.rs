@@ -1,10 +1,16 @@
-use std::collections::HashSet;
-use std::time::{ Instant, Duration };
-use std::sync::{ Arc, Mutex, atomic::{ AtomicBool, self } };
+use std::collections::{ BTreeMap, HashSet };
+use std::time::{ Instant, Duration, SystemTime, UNIX_EPOCH };
+use std::sync::{ Arc, Mutex, mpsc, atomic::{ AtomicBool, self } };
 
 use super::Author;
+use crate::pam_wrapper::AuthError;
+use crate::sessions::Session;
+use crate::state::{ FailedLogins, State };
 
-use winit::event::{ KeyboardInput, WindowEvent, ElementState, VirtualKeyCode };
+use winit::event::{
+    KeyboardInput, WindowEvent, ElementState, VirtualKeyCode,
+    MouseButton, MouseScrollDelta,
+};
 use skulpin::{
     CoordinateSystemHelper,
     skia_safe,
@@ -13,25 +19,99 @@ use skia_safe::{
     Point, Rect,
     Color, Color4f,
     Canvas, paint, Paint,
+    Font, Typeface,
 };
 
 const LOGIN_LOADING_DURATION: Duration = Duration::from_millis(2000);
 const LOGIN_ANIMATION_DURATION: Duration = Duration::from_millis(500);
+const USERNAME_MAX_LENGTH: usize = 256;
+const PROMPT_MAX_LENGTH: usize = 256;
+const MESSAGE_DURATION: Duration = Duration::from_millis(5000);
+const BOX_FLASH_DURATION: Duration = Duration::from_millis(400);
+/// Failed attempts of a user before the next ones are delayed
+const FREE_ATTEMPTS: u32 = 2;
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(2);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Where the login username comes from
+pub enum UserSource {
+    /// Picked from a list, the selection stage is skipped if there is only one
+    List(Vec<String>),
+    /// Typed by the user
+    Typed,
+}
+
+/// How long the password is expected to be
+#[derive(Clone, Copy)]
+pub enum PasswordMode {
+    /// Exactly `length` characters, one box per character
+    Fixed { length: usize },
+    /// Any length up to `max_length`, the `boxes` boxes only animate on each
+    /// keystroke so that the length isn't shown
+    Variable { boxes: usize, max_length: usize },
+}
+
+/// Answer to an expired password, sent back to the PAM thread
+pub struct PasswordChange {
+    pub current: String,
+    pub new: String,
+}
+
+const PASSWORD_CHANGE_LABELS: [&str; 3] = ["Current password", "New password", "Confirm new password"];
+
+/// Failed attempts of a user, reset on success
+#[derive(Default)]
+struct Lockout {
+    failures: u32,
+    /// No attempt is made before then
+    until: Option<Instant>,
+}
+
+/// What happened to a text input after reading the last events
+enum TextInputAction {
+    None,
+    Submit,
+    /// Backspace was pressed with nothing left to erase
+    EraseEmpty,
+}
 
 #[derive(Clone)]
 pub enum AppStage {
+    SelectingUser,
+    TypingUsername,
+    /// Answering a PAM prompt other than the password
+    Prompting {
+        message: String,
+        echo: bool,
+    },
+    /// Choosing a new password after the current one expired, field is the
+    /// index of the edited input in PASSWORD_CHANGE_LABELS
+    ChangingPassword {
+        field: usize,
+    },
     Inputing {
         ball_red_flash_duration: Duration,
         ball_red_flash_start: Instant,
     },
     Validating {
         start: Instant,
-        finished: bool,
-        succeed: bool,
+        /// None until the result is received
+        result: Option<Result<(), AuthError>>,
     },
     LoggingIn {
         start: Instant,
-    }
+    },
+    /// The session could not be started, Enter goes back to the password
+    Error {
+        message: String,
+    },
+    /// Counting down before logging the user in without password, any key
+    /// cancels
+    Autologin {
+        username: String,
+        start: Instant,
+        timeout: Duration,
+    },
 }
 
 impl AppStage {
@@ -56,8 +136,7 @@ impl AppStage {
     pub fn validating() -> Self {
         AppStage::Validating {
             start: Instant::now(),
-            finished: false,
-            succeed: false,
+            result: None,
         }
     }
 
@@ -69,15 +148,26 @@ impl AppStage {
 }
 
 pub struct App<F>
-    where F: Fn(String, String) -> ()
+    where F: Fn(String, String, Session) -> ()
 {
     login_callback: F,
 
     last_events: Vec<WindowEvent<'static>>,
     pressed_keys: HashSet<VirtualKeyCode>,
+    cursor_position: Point,
     boxes_size: f32,
-    login_username: String,
-    pass_length: usize,
+    /// Empty when the username is typed
+    users: Vec<String>,
+    selected_user: usize,
+    typed_username: String,
+    password_mode: PasswordMode,
+    /// Number of keystrokes in the password, used to animate the variable mode
+    keystrokes: u32,
+    box_flashes: Vec<Option<Instant>>,
+    sessions: Vec<Session>,
+    selected_session: usize,
+    /// Id of the last session used by each user
+    last_sessions: BTreeMap<String, String>,
 
     ball_position: f32,
     ball_velocity: f32,
@@ -85,31 +175,119 @@ pub struct App<F>
 
     last_update: Instant,
     current_input: String,
+
+    prompt_input: String,
+    prompt_reply: Option<mpsc::Sender<Option<String>>>,
+    password_change_inputs: [String; 3],
+    password_change_reply: Option<mpsc::Sender<Option<PasswordChange>>>,
+    /// Last PAM message, whether it is an error and when it was received
+    message: Option<(String, bool, Instant)>,
+    lockouts: BTreeMap<String, Lockout>,
 }
 
 /// Public methods
 impl<F> App<F>
-    where F: Fn(String, String) -> ()
+    where F: Fn(String, String, Session) -> ()
 {
-    pub fn new(login_callback: F, login_username: impl Into<String>, pass_length: usize) -> Self {
+    pub fn new(
+        login_callback: F,
+        user_source: UserSource,
+        password_mode: PasswordMode,
+        sessions: Vec<Session>,
+    ) -> Self {
+        assert!(!sessions.is_empty(), "App needs at least one session");
+        let (users, stage) = match user_source {
+            UserSource::List(users) => {
+                assert!(!users.is_empty(), "App needs at least one user");
+                let stage = if users.len() == 1 {
+                    AppStage::inputing()
+                }
+                else {
+                    AppStage::SelectingUser
+                };
+                (users, stage)
+            }
+            UserSource::Typed => (Vec::new(), AppStage::TypingUsername),
+        };
+
         Self {
             login_callback,
             
             last_events: Default::default(),
             pressed_keys: Default::default(),
+            cursor_position: Point::new(0., 0.),
             boxes_size: 100.,
-            login_username: login_username.into(),
-            pass_length,
+            users,
+            selected_user: 0,
+            typed_username: String::default(),
+            password_mode,
+            keystrokes: 0,
+            box_flashes: Vec::new(),
+            sessions,
+            selected_session: 0,
+            last_sessions: BTreeMap::new(),
 
             ball_position: 0.,
             ball_velocity: 0.,
-            stage: AppStage::inputing(),
+            stage,
 
             last_update: Instant::now(),
             current_input: String::default(),
+
+            prompt_input: String::default(),
+            prompt_reply: None,
+            password_change_inputs: Default::default(),
+            password_change_reply: None,
+            message: None,
+            lockouts: BTreeMap::new(),
         }
     }
 
+    /// Preselects the last user and their last session
+    pub fn restore_state(&mut self, state: &State) {
+        self.last_sessions = state.sessions.clone();
+
+        if let Some(last_user) = &state.last_user {
+            if self.users.is_empty() {
+                self.typed_username = last_user.clone();
+            }
+            else if let Some(i) = self.users.iter().position(|u| u == last_user) {
+                self.selected_user = i;
+            }
+        }
+        self.select_last_session();
+
+        let now = SystemTime::now();
+        self.lockouts = state.failed_logins.iter()
+            .map(|(username, failed)| {
+                let until = failed.locked_until
+                    .map(|until| UNIX_EPOCH + Duration::from_secs(until))
+                    .and_then(|until| until.duration_since(now).ok())
+                    .map(|remaining| Instant::now() + remaining);
+                (username.clone(), Lockout { failures: failed.count, until })
+            })
+            .collect();
+    }
+
+    /// Saves the failed attempts in the state, so that restarting the greeter
+    /// doesn't reset the delays. Only those of existing users, so that typed
+    /// names can't grow the file. Unknown ones are still delayed until the
+    /// greeter restarts, not to be told apart
+    pub fn store_failed_logins(&self, state: &mut State) {
+        let now = SystemTime::now();
+        state.failed_logins = self.lockouts.iter()
+            .filter(|(username, _)| users::get_user_by_name(username.as_str()).is_some())
+            .map(|(username, lockout)| {
+                let locked_until = lockout.until
+                    .and_then(|until| until.checked_duration_since(Instant::now()))
+                    .map(|remaining| (now + remaining).duration_since(UNIX_EPOCH).unwrap_or_default())
+                    // Rounded up so that the delay isn't shortened
+                    .map(|until| until.as_secs() + 1);
+                (username.clone(), FailedLogins { count: lockout.failures, locked_until })
+            })
+            .collect();
+    }
+
     pub fn add_window_event(&mut self, we: WindowEvent<'static>) {
         match &we {
             WindowEvent::KeyboardInput {
@@ -123,6 +301,9 @@ impl<F> App<F>
                     self.pressed_keys.remove(vkc);
                 }
             }
+            WindowEvent::CursorMoved { position, .. } => {
+                self.cursor_position = Point::new(position.x as f32, position.y as f32);
+            }
             _ => (),
         }
 
@@ -134,19 +315,58 @@ impl<F> App<F>
         canvas: &mut Canvas,
         coordinate_system_helper: CoordinateSystemHelper,
     ) {
+        let extents = coordinate_system_helper.surface_extents();
         self.update(self.last_update.elapsed().as_secs_f32());
         self.draw(canvas, coordinate_system_helper);
+        self.draw_message(canvas, extents.width as f32);
         self.last_events.clear();
 
         self.last_update = Instant::now();
     }
 
-    /// Returns the amount of time the ending animation will last
-    pub fn login_result(&mut self, s: bool) -> Duration {
+    /// Asks the user to answer a PAM prompt, the answer (or None if cancelled)
+    /// is sent through reply
+    pub fn pam_prompt(&mut self, message: String, echo: bool, reply: mpsc::Sender<Option<String>>) {
+        self.prompt_input.clear();
+        self.prompt_reply = Some(reply);
+        self.stage = AppStage::Prompting { message, echo };
+    }
+
+    /// Asks the user for their current password and a new one, the answer (or
+    /// None if cancelled) is sent through reply
+    pub fn password_change(&mut self, reply: mpsc::Sender<Option<PasswordChange>>) {
+        self.password_change_inputs = Default::default();
+        self.password_change_reply = Some(reply);
+        self.stage = AppStage::ChangingPassword { field: 0 };
+    }
+
+    /// Shows an informational or error message from PAM, pam_faillock's
+    /// remaining lock time is shown as a countdown
+    pub fn pam_message(&mut self, message: String, error: bool) {
+        if let Some(remaining) = faillock_remaining(&message) {
+            self.lock_user_for(remaining);
+        }
+        self.message = Some((message, error, Instant::now()));
+    }
+
+    /// Returns the amount of time the ending animation will last. Failed
+    /// attempts are counted right away, see [App::store_failed_logins]
+    pub fn login_result(&mut self, r: Result<(), AuthError>) -> Duration {
+        match &r {
+            Ok(()) => {
+                let username = self.login_username().to_string();
+                self.lockouts.remove(&username);
+            }
+            // Not when the user cancelled or PAM failed on its own
+            Err(AuthError::WrongPassword | AuthError::MaxTries | AuthError::AccountLocked) => {
+                self.record_failure();
+            }
+            Err(_) => (),
+        }
+
         match &mut self.stage {
-            AppStage::Validating { finished, succeed, .. } => {
-                *finished = true;
-                *succeed = s;
+            AppStage::Validating { result, .. } => {
+                *result = Some(r);
 
                 LOGIN_ANIMATION_DURATION + LOGIN_LOADING_DURATION
             }
@@ -154,11 +374,58 @@ impl<F> App<F>
             _ => panic!("Called login_result with the app in the wrong stage"),
         }
     }
+
+    /// Preselects the user and the session (their last one if None), then
+    /// counts down until [App::autologin_timeout] is called
+    pub fn start_autologin(&mut self, username: String, session_id: Option<&str>, timeout: Duration) {
+        if self.users.is_empty() {
+            self.typed_username = username.clone();
+        }
+        else {
+            // Kiosk accounts are often outside of the listed uids
+            self.selected_user = self.users.iter().position(|u| *u == username)
+                .unwrap_or_else(|| {
+                    self.users.push(username.clone());
+                    self.users.len() - 1
+                });
+        }
+        self.select_last_session();
+        if let Some(i) = session_id.and_then(|id| self.sessions.iter().position(|s| s.id == id)) {
+            self.selected_session = i;
+        }
+        self.stage = AppStage::Autologin { username, start: Instant::now(), timeout };
+    }
+
+    /// The user and session to log in without password, None if the user
+    /// cancelled the countdown
+    pub fn autologin_timeout(&mut self) -> Option<(String, Session)> {
+        let username = match &self.stage {
+            AppStage::Autologin { username, .. } => username.clone(),
+            _ => return None,
+        };
+        self.stage = AppStage::validating();
+        Some((username, self.sessions[self.selected_session].clone()))
+    }
+
+    /// Back to the start for the next user, once a session started on another
+    /// VT while the greeter stays up
+    pub fn session_started(&mut self) {
+        self.current_input.clear();
+        self.typed_username.clear();
+        self.message = None;
+        self.stage = self.user_stage().unwrap_or_else(AppStage::inputing);
+    }
+
+    /// Shows why the session could not be started until the user retries
+    pub fn session_error(&mut self, message: String) {
+        self.current_input.clear();
+        self.stage = AppStage::Error { message };
+    }
 }
 
 /// Private methods
 impl<F> App<F>
-    where F: Fn(String, String) -> (),
+    where F: Fn(String, String, Session) -> (),
 {
     fn is_key_just_pressed(&self, vck: VirtualKeyCode) -> bool {
         self.last_events.iter().any(|we|
@@ -184,14 +451,542 @@ impl<F> App<F>
         self.pressed_keys.contains(&vck)
     }
 
+    /// How long the current user must wait before trying again, if they must
+    fn lockout_remaining(&self) -> Option<Duration> {
+        self.lockouts.get(self.login_username())?
+            .until?
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    fn lock_user_for(&mut self, duration: Duration) {
+        let username = self.login_username().to_string();
+        let lockout = self.lockouts.entry(username).or_default();
+        let until = Instant::now() + duration;
+        lockout.until = Some(lockout.until.map_or(until, |u| u.max(until)));
+    }
+
+    /// Counts a failed attempt of the current user, delaying the next one
+    /// more and more once the free attempts are used
+    fn record_failure(&mut self) {
+        let username = self.login_username().to_string();
+        let lockout = self.lockouts.entry(username).or_default();
+        lockout.failures += 1;
+        if lockout.failures > FREE_ATTEMPTS {
+            let doublings = (lockout.failures - FREE_ATTEMPTS - 1).min(16);
+            self.lock_user_for((BASE_RETRY_DELAY * (1 << doublings)).min(MAX_RETRY_DELAY));
+        }
+    }
+
+    fn login_username(&self) -> &str {
+        if self.users.is_empty() {
+            &self.typed_username
+        }
+        else {
+            &self.users[self.selected_user]
+        }
+    }
+
+    fn boxes_count(&self) -> usize {
+        match self.password_mode {
+            PasswordMode::Fixed { length } => length,
+            PasswordMode::Variable { boxes, .. } => boxes,
+        }
+    }
+
+    fn max_password_length(&self) -> usize {
+        match self.password_mode {
+            PasswordMode::Fixed { length } => length,
+            PasswordMode::Variable { max_length, .. } => max_length,
+        }
+    }
+
+    /// Whether the current input can be submitted, never when empty so that a
+    /// stray Enter doesn't count as a failed attempt
+    fn is_password_complete(&self) -> bool {
+        match self.password_mode {
+            PasswordMode::Fixed { length } => self.current_input.chars().count() >= length,
+            PasswordMode::Variable { .. } => !self.current_input.is_empty(),
+        }
+    }
+
+    /// How white the box at the given index (from the bottom) is, between 0 and 1
+    fn box_fill(&self, index: usize) -> f32 {
+        match self.password_mode {
+            PasswordMode::Fixed { .. } => {
+                if index < self.current_input.chars().count() { 1. } else { 0. }
+            }
+            PasswordMode::Variable { .. } => {
+                self.box_flashes.get(index).copied().flatten()
+                    .map(|start| start.elapsed().as_secs_f32() / BOX_FLASH_DURATION.as_secs_f32())
+                    .map(|factor| (1. - factor).max(0.))
+                    .unwrap_or(0.)
+            }
+        }
+    }
+
+    /// Start angle and sweep (in degrees) of the white progress fill of the ball
+    fn progress_arc(&self) -> (f32, f32) {
+        match self.password_mode {
+            PasswordMode::Fixed { length } => {
+                (0., (self.current_input.chars().count() as f32 / length as f32) * 360.)
+            }
+            // Only moves around on each keystroke
+            PasswordMode::Variable { .. } if !self.current_input.is_empty() => {
+                ((self.keystrokes as f32 * 137.) % 360., 120.)
+            }
+            PasswordMode::Variable { .. } => (0., 0.),
+        }
+    }
+
+    /// Animates the variable password mode after the input changed
+    fn password_keystroke(&mut self) {
+        if let PasswordMode::Variable { boxes, .. } = self.password_mode {
+            self.keystrokes = self.keystrokes.wrapping_add(1);
+            // Scrambles the keystrokes count so the flashing box looks random
+            let index = (self.keystrokes.wrapping_mul(2654435761) >> 16) as usize % boxes;
+            self.box_flashes.resize(boxes, None);
+            self.box_flashes[index] = Some(Instant::now());
+            self.ball_velocity = self.ball_velocity.max(0.) + 8.;
+        }
+    }
+
+    /// Reads the inputs changing the selected session and draws its name at
+    /// the bottom of the screen
+    fn draw_session_choice(&mut self, canvas: &mut Canvas, width: f32, height: f32) {
+        let font = Font::from_typeface(Typeface::default(), 24.);
+        let mut text_paint = Paint::new(Color4f::new(0.6, 0.6, 0.6, 1.), None);
+        text_paint.set_anti_alias(true);
+        let center = Point::new(width / 2., height - 60.);
+
+        let mut offset: isize = 0;
+        for event in &self.last_events {
+            match event {
+                WindowEvent::KeyboardInput { input: KeyboardInput {
+                    state: ElementState::Pressed,
+                    virtual_keycode: Some(vkc), ..
+                }, .. } => match vkc {
+                    VirtualKeyCode::Left => offset -= 1,
+                    VirtualKeyCode::Right => offset += 1,
+                    _ => (),
+                },
+
+                WindowEvent::MouseInput {
+                    state: ElementState::Pressed,
+                    button: MouseButton::Left, ..
+                } if (self.cursor_position.y - center.y).abs() < 30. => {
+                    if self.cursor_position.x < center.x {
+                        offset -= 1;
+                    }
+                    else {
+                        offset += 1;
+                    }
+                }
+
+                _ => (),
+            }
+        }
+        let count = self.sessions.len() as isize;
+        self.selected_session = (self.selected_session as isize + offset).rem_euclid(count) as usize;
+
+        let label = if self.sessions.len() > 1 {
+            format!("<  {}  >", self.sessions[self.selected_session].name)
+        }
+        else {
+            self.sessions[self.selected_session].name.clone()
+        };
+        draw_centered_text(canvas, &label, center, &font, &text_paint);
+    }
+
+    fn select_last_session(&mut self) {
+        let last_session = self.last_sessions.get(self.login_username())
+            .and_then(|id| self.sessions.iter().position(|s| &s.id == id));
+        if let Some(i) = last_session {
+            self.selected_session = i;
+        }
+    }
+
+    /// Goes to the password input once the user is chosen
+    fn enter_password_stage(&mut self) {
+        self.ball_position = 0.;
+        self.ball_velocity = 0.;
+        self.current_input.clear();
+        self.select_last_session();
+        self.stage = AppStage::inputing();
+    }
+
+    fn draw_prompt(&mut self, canvas: &mut Canvas, width: f32, height: f32) {
+        let (message, echo) = match &self.stage {
+            AppStage::Prompting { message, echo } => (message.clone(), *echo),
+            _ => unreachable!(),
+        };
+
+        let mut input = std::mem::take(&mut self.prompt_input);
+        let action = self.read_text_input(&mut input, PROMPT_MAX_LENGTH);
+        self.prompt_input = input;
+
+        let answer = match action {
+            TextInputAction::Submit => Some(Some(std::mem::take(&mut self.prompt_input))),
+            // Cancelling the conversation, which fails the login
+            TextInputAction::EraseEmpty => Some(None),
+            TextInputAction::None => None,
+        };
+        if let Some(answer) = answer {
+            if let Some(reply) = self.prompt_reply.take() {
+                let _ = reply.send(answer);
+            }
+            self.stage = AppStage::validating();
+            return;
+        }
+
+        let font = Font::from_typeface(Typeface::default(), 32.);
+        let mut text_paint = Paint::new(Color4f::new(0.6, 0.6, 0.6, 1.), None);
+        text_paint.set_anti_alias(true);
+        draw_centered_text(canvas, &message, Point::new(width / 2., height / 2. - 50.), &font, &text_paint);
+
+        text_paint.set_color4f(Color4f::new(1., 1., 1., 1.), None);
+        let shown_input = if echo {
+            self.prompt_input.clone()
+        }
+        else {
+            "*".repeat(self.prompt_input.chars().count())
+        };
+        draw_centered_text(canvas, &shown_input, Point::new(width / 2., height / 2. + 50.), &font, &text_paint);
+    }
+
+    fn draw_password_change(&mut self, canvas: &mut Canvas, width: f32, height: f32) {
+        let mut field = match self.stage {
+            AppStage::ChangingPassword { field } => field,
+            _ => unreachable!(),
+        };
+
+        let mut input = std::mem::take(&mut self.password_change_inputs[field]);
+        let action = self.read_text_input(&mut input, PROMPT_MAX_LENGTH);
+        self.password_change_inputs[field] = input;
+
+        let mut answer = None;
+        match action {
+            TextInputAction::Submit if field < 2 => field += 1,
+            TextInputAction::Submit => {
+                let [current, new, confirm] = std::mem::take(&mut self.password_change_inputs);
+                if new.is_empty() || new != confirm {
+                    self.pam_message(String::from("The new passwords do not match"), true);
+                    self.password_change_inputs[0] = current;
+                    field = 1;
+                }
+                else {
+                    answer = Some(Some(PasswordChange { current, new }));
+                }
+            }
+            // Cancelling on the first field, which fails the login
+            TextInputAction::EraseEmpty if field == 0 => answer = Some(None),
+            TextInputAction::EraseEmpty => field -= 1,
+            TextInputAction::None => (),
+        }
+
+        if let Some(answer) = answer {
+            if let Some(reply) = self.password_change_reply.take() {
+                let _ = reply.send(answer);
+            }
+            self.stage = AppStage::validating();
+            return;
+        }
+        self.stage = AppStage::ChangingPassword { field };
+
+        let font = Font::from_typeface(Typeface::default(), 32.);
+        let mut text_paint = Paint::new(Color4f::new(1., 1., 1., 1.), None);
+        text_paint.set_anti_alias(true);
+        draw_centered_text(
+            canvas, "Your password expired",
+            Point::new(width / 2., height / 2. - 200.),
+            &font, &text_paint,
+        );
+
+        for (i, label) in PASSWORD_CHANGE_LABELS.iter().enumerate() {
+            let y = height / 2. - 60. + i as f32 * 100.;
+            let brightness = if i == field { 1. } else { 0.4 };
+            text_paint.set_color4f(Color4f::new(brightness, brightness, brightness, 1.), None);
+            draw_centered_text(canvas, label, Point::new(width / 2., y), &font, &text_paint);
+            draw_centered_text(
+                canvas, &"*".repeat(self.password_change_inputs[i].chars().count()),
+                Point::new(width / 2., y + 40.),
+                &font, &text_paint,
+            );
+        }
+    }
+
+    fn draw_autologin(&mut self, canvas: &mut Canvas, width: f32, height: f32) {
+        let (username, remaining) = match &self.stage {
+            AppStage::Autologin { username, start, timeout } => {
+                (username.clone(), timeout.saturating_sub(start.elapsed()))
+            }
+            _ => unreachable!(),
+        };
+
+        let cancelled = self.last_events.iter().any(|event| matches!(
+            event,
+            WindowEvent::KeyboardInput { input: KeyboardInput { state: ElementState::Pressed, .. }, .. }
+            | WindowEvent::MouseInput { state: ElementState::Pressed, .. }
+        ));
+        if cancelled {
+            match self.user_stage() {
+                Some(stage) => self.stage = stage,
+                None => self.enter_password_stage(),
+            }
+            return;
+        }
+
+        let font = Font::from_typeface(Typeface::default(), 32.);
+        let mut text_paint = Paint::new(Color4f::new(1., 1., 1., 1.), None);
+        text_paint.set_anti_alias(true);
+        draw_centered_text(
+            canvas, &format!("Logging in as {username}"),
+            Point::new(width / 2., height / 2. - 100.),
+            &font, &text_paint,
+        );
+        draw_centered_text(
+            canvas, &self.sessions[self.selected_session].name,
+            Point::new(width / 2., height / 2. - 50.),
+            &font, &text_paint,
+        );
+
+        let countdown_font = Font::from_typeface(Typeface::default(), 96.);
+        draw_centered_text(
+            canvas, &format_countdown(remaining),
+            Point::new(width / 2., height / 2. + 60.),
+            &countdown_font, &text_paint,
+        );
+
+        text_paint.set_color4f(Color4f::new(0.6, 0.6, 0.6, 1.), None);
+        draw_centered_text(
+            canvas, "Press any key to cancel",
+            Point::new(width / 2., height / 2. + 200.),
+            &font, &text_paint,
+        );
+    }
+
+    fn draw_error(&mut self, canvas: &mut Canvas, width: f32, height: f32) {
+        let message = match &self.stage {
+            AppStage::Error { message } => message.clone(),
+            _ => unreachable!(),
+        };
+
+        if self.is_key_just_pressed(VirtualKeyCode::Return) {
+            self.enter_password_stage();
+            return;
+        }
+
+        let font = Font::from_typeface(Typeface::default(), 32.);
+        let mut text_paint = Paint::new(Color4f::new(1., 0.2, 0.2, 1.), None);
+        text_paint.set_anti_alias(true);
+        draw_centered_text(
+            canvas, "Could not start the session",
+            Point::new(width / 2., height / 2. - 100.),
+            &font, &text_paint,
+        );
+
+        text_paint.set_color4f(Color4f::new(0.6, 0.6, 0.6, 1.), None);
+        let small_font = Font::from_typeface(Typeface::default(), 24.);
+        for (i, line) in message.lines().enumerate() {
+            let y = height / 2. - 30. + i as f32 * 34.;
+            draw_centered_text(canvas, line, Point::new(width / 2., y), &small_font, &text_paint);
+        }
+
+        text_paint.set_color4f(Color4f::new(1., 1., 1., 1.), None);
+        draw_centered_text(
+            canvas, "Press Enter to retry",
+            Point::new(width / 2., height / 2. + 200.),
+            &font, &text_paint,
+        );
+    }
+
+    /// Draws the last PAM message at the top of the screen, fading out
+    fn draw_message(&self, canvas: &mut Canvas, width: f32) {
+        let (message, error, received) = match &self.message {
+            Some(message) => message,
+            None => return,
+        };
+        let elapsed = received.elapsed();
+        if elapsed > MESSAGE_DURATION {
+            return;
+        }
+
+        let alpha = 1. - elapsed.as_secs_f32() / MESSAGE_DURATION.as_secs_f32();
+        let color = if *error {
+            Color4f::new(1., 0.2, 0.2, alpha)
+        }
+        else {
+            Color4f::new(1., 1., 1., alpha)
+        };
+        let font = Font::from_typeface(Typeface::default(), 24.);
+        let mut text_paint = Paint::new(color, None);
+        text_paint.set_anti_alias(true);
+        draw_centered_text(canvas, message, Point::new(width / 2., 60.), &font, &text_paint);
+    }
+
+    /// The stage used to choose the user, if there is a choice to make
+    fn user_stage(&self) -> Option<AppStage> {
+        if self.users.is_empty() {
+            Some(AppStage::TypingUsername)
+        }
+        else if self.users.len() > 1 {
+            Some(AppStage::SelectingUser)
+        }
+        else {
+            None
+        }
+    }
+
+    /// Applies the last keyboard events to the given text, which cannot grow
+    /// past max_chars characters
+    fn read_text_input(&self, input: &mut String, max_chars: usize) -> TextInputAction {
+        for event in &self.last_events {
+            match event {
+                WindowEvent::KeyboardInput { input: KeyboardInput {
+                    state: ElementState::Pressed,
+                    virtual_keycode: Some(VirtualKeyCode::Return), ..
+                }, .. } => {
+                    return TextInputAction::Submit;
+                }
+
+                WindowEvent::KeyboardInput { input: KeyboardInput {
+                    state: ElementState::Pressed,
+                    virtual_keycode: Some(VirtualKeyCode::Back), ..
+                }, .. } => {
+                    if input.pop().is_none() {
+                        return TextInputAction::EraseEmpty;
+                    }
+                }
+
+                WindowEvent::ReceivedCharacter(c) if c.is_alphanumeric() || c.is_ascii_punctuation() => {
+                    if input.chars().count() < max_chars {
+                        input.push(*c);
+                    }
+                },
+
+                _ => (),
+            }
+        }
+
+        TextInputAction::None
+    }
+
+    fn draw_username_input(&mut self, canvas: &mut Canvas, width: f32, height: f32) {
+        let font = Font::from_typeface(Typeface::default(), 48.);
+        let mut text_paint = Paint::new(Color4f::new(1., 1., 1., 1.), None);
+        text_paint.set_anti_alias(true);
+
+        let mut username = std::mem::take(&mut self.typed_username);
+        let action = self.read_text_input(&mut username, USERNAME_MAX_LENGTH);
+        self.typed_username = username;
+
+        if let TextInputAction::Submit = action {
+            if !self.typed_username.is_empty() {
+                self.enter_password_stage();
+                return;
+            }
+        }
+
+        let center = Point::new(width / 2., height / 2.);
+        if self.typed_username.is_empty() {
+            text_paint.set_color4f(Color4f::new(0.4, 0.4, 0.4, 1.), None);
+            draw_centered_text(canvas, "username", center, &font, &text_paint);
+        }
+        else {
+            draw_centered_text(canvas, &self.typed_username, center, &font, &text_paint);
+        }
+    }
+
+    fn cycle_user(&mut self, offset: isize) {
+        let count = self.users.len() as isize;
+        self.selected_user = (self.selected_user as isize + offset).rem_euclid(count) as usize;
+    }
+
+    fn draw_user_selection(&mut self, canvas: &mut Canvas, width: f32, height: f32) {
+        let font = Font::from_typeface(Typeface::default(), 48.);
+        let mut text_paint = Paint::new(Color4f::new(1., 1., 1., 1.), None);
+        text_paint.set_anti_alias(true);
+
+        let (name_width, _) = font.measure_str(self.login_username(), Some(&text_paint));
+        let center = Point::new(width / 2., height / 2.);
+
+        let mut offset = 0;
+        let mut selected = false;
+        for event in &self.last_events {
+            match event {
+                WindowEvent::KeyboardInput { input: KeyboardInput {
+                    state: ElementState::Pressed,
+                    virtual_keycode: Some(vkc), ..
+                }, .. } => match vkc {
+                    VirtualKeyCode::Left | VirtualKeyCode::Up => offset -= 1,
+                    VirtualKeyCode::Right | VirtualKeyCode::Down => offset += 1,
+                    VirtualKeyCode::Return => selected = true,
+                    _ => (),
+                },
+
+                WindowEvent::MouseInput {
+                    state: ElementState::Pressed,
+                    button: MouseButton::Left, ..
+                } => {
+                    let dx = self.cursor_position.x - center.x;
+                    if dx < -name_width / 2. {
+                        offset -= 1;
+                    }
+                    else if dx > name_width / 2. {
+                        offset += 1;
+                    }
+                    else {
+                        selected = true;
+                    }
+                }
+
+                WindowEvent::MouseWheel { delta, .. } => {
+                    let dy = match delta {
+                        MouseScrollDelta::LineDelta(_, y) => *y as f64,
+                        MouseScrollDelta::PixelDelta(p) => p.y,
+                    };
+                    if dy > 0. {
+                        offset -= 1;
+                    }
+                    else if dy < 0. {
+                        offset += 1;
+                    }
+                }
+
+                _ => (),
+            }
+        }
+
+        self.cycle_user(offset);
+
+        if selected {
+            self.enter_password_stage();
+            return;
+        }
+
+        let name = self.login_username();
+        let (name_width, _) = font.measure_str(name, Some(&text_paint));
+        let arrows_gap = name_width / 2. + 60.;
+        draw_centered_text(canvas, name, center, &font, &text_paint);
+        draw_centered_text(canvas, "<", Point::new(center.x - arrows_gap, center.y), &font, &text_paint);
+        draw_centered_text(canvas, ">", Point::new(center.x + arrows_gap, center.y), &font, &text_paint);
+    }
+
     fn update(&mut self, delta_t: f32) {
         let mut new_stage = None;
         match &mut self.stage {
+            AppStage::SelectingUser | AppStage::TypingUsername
+            | AppStage::Prompting { .. } | AppStage::ChangingPassword { .. }
+            | AppStage::Error { .. } | AppStage::Autologin { .. } => (),
+
             AppStage::Inputing { .. } => {
                 self.ball_velocity -= 30. * delta_t;
                 self.ball_position += self.ball_velocity * delta_t;
 
-                let ball_min = self.current_input.chars().count() as f32;
+                let ball_min = match self.password_mode {
+                    PasswordMode::Fixed { .. } => self.current_input.chars().count() as f32,
+                    PasswordMode::Variable { .. } => 0.,
+                };
                 if self.ball_position < ball_min {
                     let diff = ball_min - self.ball_position;
                     let bounce = if diff < 0.2 { 0. } else { diff * 10. };
@@ -201,20 +996,24 @@ impl<F> App<F>
                 }
             },
 
-            AppStage::Validating { start, finished, succeed, .. } => {
-                if start.elapsed() > LOGIN_LOADING_DURATION && *finished {
-                    if *succeed {
+            AppStage::Validating { start, result: Some(result), .. }
+                if start.elapsed() > LOGIN_LOADING_DURATION =>
+            {
+                match result {
+                    Ok(()) => {
                         new_stage = Some(AppStage::logging_in());
                     }
-                    else {
+                    Err(e) => {
                         self.current_input.clear();
+                        self.message = Some((e.to_string(), true, Instant::now()));
                         new_stage = Some(AppStage::inputing().with_red_flash(Duration::from_millis(2000)));
                     }
                 }
-                else {
-                    self.ball_velocity = self.ball_velocity / (1. + delta_t * 10.);
-                    self.ball_position += (self.ball_velocity + 1.) * delta_t;
-                }
+            }
+
+            AppStage::Validating { .. } => {
+                self.ball_velocity = self.ball_velocity / (1. + delta_t * 10.);
+                self.ball_position += (self.ball_velocity + 1.) * delta_t;
             },
 
             AppStage::LoggingIn { .. } => (),
@@ -241,6 +1040,34 @@ impl<F> App<F>
             (extents.width as f32, extents.height as f32);
         canvas.clear(Color::from_rgb(0, 0, 0));
 
+        match self.stage {
+            AppStage::SelectingUser => {
+                self.draw_user_selection(canvas, width, height);
+                return;
+            }
+            AppStage::TypingUsername => {
+                self.draw_username_input(canvas, width, height);
+                return;
+            }
+            AppStage::Prompting { .. } => {
+                self.draw_prompt(canvas, width, height);
+                return;
+            }
+            AppStage::ChangingPassword { .. } => {
+                self.draw_password_change(canvas, width, height);
+                return;
+            }
+            AppStage::Error { .. } => {
+                self.draw_error(canvas, width, height);
+                return;
+            }
+            AppStage::Autologin { .. } => {
+                self.draw_autologin(canvas, width, height);
+                return;
+            }
+            _ => (),
+        }
+
         let mut fill_paint = Paint::new(Color4f::new(1.0, 1.0, 1.0, 1.0), None);
         fill_paint.set_anti_alias(true);
         fill_paint.set_style(paint::Style::Fill);
@@ -249,7 +1076,8 @@ impl<F> App<F>
         stroke_paint.set_anti_alias(true);
         stroke_paint.set_style(paint::Style::Stroke);
 
-        let full_rect_height = self.boxes_size * (self.pass_length + 1) as f32;
+        let boxes_count = self.boxes_count();
+        let full_rect_height = self.boxes_size * (boxes_count + 1) as f32;
 
         /*
          * Drawing of squares and black outlines
@@ -257,7 +1085,7 @@ impl<F> App<F>
         fill_paint.set_color4f(Color4f::new(1., 1., 1., 1.), None);
         stroke_paint.set_stroke_width(boxes_gaps);
         stroke_paint.set_color4f(Color4f::new(0., 0., 0., 1.), None);
-        for i in 0..self.pass_length {
+        for i in 0..boxes_count {
             let x = width / 2. - self.boxes_size / 2.;
             let y = height / 2. + full_rect_height / 2. - (i as f32 + 1.) * self.boxes_size;
             let rect = Rect::new(
@@ -265,14 +1093,8 @@ impl<F> App<F>
                 x + self.boxes_size, y + self.boxes_size,
             );
 
-            fill_paint.set_color4f(
-                if i < self.current_input.chars().count() {
-                    Color4f::new(1., 1., 1., 1.)
-                }
-                else {
-                    Color4f::new(0., 0., 0., 1.)
-                }
-            , None);
+            let fill = self.box_fill(i);
+            fill_paint.set_color4f(Color4f::new(fill, fill, fill, 1.), None);
 
             canvas.draw_rect(rect, &fill_paint);
             canvas.draw_line(
@@ -324,43 +1146,46 @@ impl<F> App<F>
         match self.stage {
             AppStage::Inputing { ball_red_flash_start, ball_red_flash_duration, .. } => {
                 // Reading inputs
-                for event in &self.last_events {
-                    match event {
-                        WindowEvent::KeyboardInput { input: KeyboardInput {
-                            state: ElementState::Pressed,
-                            virtual_keycode: Some(VirtualKeyCode::Return), ..
-                        }, .. } => {
-                            if self.current_input.len() < self.pass_length {
-                                next_stage = Some(self.stage.with_red_flash(
-                                    Duration::from_millis(500)
-                                ));
-                            }
-                            else {
-                                (self.login_callback)(
-                                    self.login_username.clone(),
-                                    self.current_input.clone(),
-                                );
-                                next_stage = Some(AppStage::validating());
-                            }
-                        }
+                let mut input = self.current_input.clone();
+                let action = self.read_text_input(&mut input, self.max_password_length());
+                let changed = input != self.current_input;
+                self.current_input = input;
+                if changed {
+                    self.password_keystroke();
+                }
 
-                        WindowEvent::KeyboardInput { input: KeyboardInput {
-                            state: ElementState::Pressed,
-                            virtual_keycode: Some(VirtualKeyCode::Back), ..
-                        }, .. } => {
-                            self.current_input.pop();
+                match action {
+                    TextInputAction::Submit => {
+                        if let Some(remaining) = self.lockout_remaining() {
+                            let message = format!("Try again in {}", format_countdown(remaining));
+                            self.message = Some((message, true, Instant::now()));
+                            next_stage = Some(self.stage.with_red_flash(
+                                Duration::from_millis(500)
+                            ));
+                        }
+                        else if !self.is_password_complete() {
+                            next_stage = Some(self.stage.with_red_flash(
+                                Duration::from_millis(500)
+                            ));
+                        }
+                        else {
+                            (self.login_callback)(
+                                self.login_username().to_string(),
+                                self.current_input.clone(),
+                                self.sessions[self.selected_session].clone(),
+                            );
+                            next_stage = Some(AppStage::validating());
                         }
-
-                        WindowEvent::ReceivedCharacter(c) if c.is_alphanumeric() || c.is_ascii_punctuation() => {
-                            if self.current_input.chars().count() < self.pass_length {
-                                self.current_input.push(*c);
-                            }
-                        },
-
-                        _ => (),
                     }
+                    // Going back to the user choice when there is nothing left to erase
+                    TextInputAction::EraseEmpty => {
+                        next_stage = self.user_stage();
+                    }
+                    TextInputAction::None => (),
                 }
 
+                self.draw_session_choice(canvas, width, height);
+
                 /*
                  * Drawing the balll
                  */
@@ -374,6 +1199,10 @@ impl<F> App<F>
                 canvas.draw_circle(ball_center, ball_radius, &fill_paint);
             }
 
+            AppStage::SelectingUser | AppStage::TypingUsername
+            | AppStage::Prompting { .. } | AppStage::ChangingPassword { .. }
+            | AppStage::Error { .. } | AppStage::Autologin { .. } => unreachable!(),
+
             AppStage::Validating { .. } => {
 
             },
@@ -392,6 +1221,7 @@ impl<F> App<F>
          */
         // WHITE PROGRESS FILL
         fill_paint.set_color4f(Color4f::new(1., 1., 1., 1.), None);
+        let (arc_start, arc_sweep) = self.progress_arc();
         canvas.draw_arc(
             Rect::new(
                 ball_center.x - ball_radius,
@@ -399,7 +1229,7 @@ impl<F> App<F>
                 ball_center.x + ball_radius,
                 ball_center.y + ball_radius,
             ),
-            0., (self.current_input.chars().count() as f32 / self.pass_length as f32) * 360.,
+            arc_start, arc_sweep,
             true,
             &fill_paint,
         );
@@ -412,8 +1242,84 @@ impl<F> App<F>
             &stroke_paint,
         );
 
+        // RED COUNTDOWN until the next attempt
+        if let AppStage::Inputing { .. } = self.stage {
+            if let Some(remaining) = self.lockout_remaining() {
+                let font = Font::from_typeface(Typeface::default(), ball_radius * 0.8);
+                let mut text_paint = Paint::new(Color4f::new(1., 0.2, 0.2, 1.), None);
+                text_paint.set_anti_alias(true);
+                draw_centered_text(canvas, &format_countdown(remaining), ball_center, &font, &text_paint);
+            }
+        }
+
         if let Some(next) = next_stage {
             self.stage = next;
         }
     }
 }
+
+/// "42" for seconds, "14:59" past a minute
+fn format_countdown(remaining: Duration) -> String {
+    let seconds = remaining.as_secs_f32().ceil() as u64;
+    if seconds < 60 {
+        seconds.to_string()
+    }
+    else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+/// Time left before pam_faillock unlocks the account, from its
+/// "(15 minutes left to unlock)" message
+fn faillock_remaining(message: &str) -> Option<Duration> {
+    let (before, _) = message.split_once("left to unlock")?;
+    let mut words = before
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .rev();
+    let unit = words.next()?;
+    let count: u64 = words.next()?.parse().ok()?;
+    match unit {
+        "minute" | "minutes" => Some(Duration::from_secs(count * 60)),
+        "second" | "seconds" => Some(Duration::from_secs(count)),
+        _ => None,
+    }
+}
+
+/// Draws the text with its center (horizontally and vertically) at the given point
+fn draw_centered_text(canvas: &mut Canvas, text: &str, center: Point, font: &Font, paint: &Paint) {
+    let (_, bounds) = font.measure_str(text, Some(paint));
+    canvas.draw_str(
+        text,
+        Point::new(center.x - bounds.center_x(), center.y - bounds.center_y()),
+        font, paint,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn countdown() {
+        assert_eq!(format_countdown(Duration::ZERO), "0");
+        assert_eq!(format_countdown(Duration::from_millis(4200)), "5");
+        assert_eq!(format_countdown(Duration::from_secs(59)), "59");
+        assert_eq!(format_countdown(Duration::from_secs(60)), "1:00");
+        assert_eq!(format_countdown(Duration::from_secs(899)), "14:59");
+    }
+
+    #[test]
+    fn faillock_messages() {
+        let remaining = faillock_remaining;
+        assert_eq!(
+            remaining("The account is locked due to 3 failed logins.\n(15 minutes left to unlock)"),
+            Some(Duration::from_secs(15 * 60))
+        );
+        assert_eq!(remaining("(1 minute left to unlock)"), Some(Duration::from_secs(60)));
+        assert_eq!(remaining("(42 seconds left to unlock)"), Some(Duration::from_secs(42)));
+        assert_eq!(remaining("(a few minutes left to unlock)"), None);
+        assert_eq!(remaining("(3 hours left to unlock)"), None);
+        assert_eq!(remaining("The account is locked due to 3 failed logins."), None);
+    }
+}
@@ -0,0 +1,417 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{ Path, PathBuf };
+
+use anyhow::{ anyhow, bail, ensure, Context };
+use serde::Deserialize;
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/himmel/config.toml";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub login: LoginConfig,
+    #[serde(default)]
+    pub users: UsersConfig,
+    #[serde(default)]
+    pub sessions: SessionsConfig,
+    #[serde(default)]
+    pub x_server: XServerConfig,
+    #[serde(default)]
+    pub pam: PamConfig,
+    #[serde(default)]
+    pub state: StateConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub autologin: AutologinConfig,
+}
+
+/// How long passwords are expected to be
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PasswordMode {
+    /// Exactly `pass_length` characters
+    Fixed,
+    /// Any length up to `max_length`, `pass_length` is then the number of boxes shown
+    Variable,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoginConfig {
+    pub password_mode: PasswordMode,
+    pub pass_length: usize,
+    pub max_length: usize,
+}
+
+impl Default for LoginConfig {
+    fn default() -> Self {
+        Self {
+            password_mode: PasswordMode::Fixed,
+            pass_length: 4,
+            max_length: 256,
+        }
+    }
+}
+
+/// How the login username is chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UsersMode {
+    /// Picked from the accounts of the system user database
+    List,
+    /// Typed by the user, for accounts that cannot be enumerated (LDAP, SSSD...)
+    Typed,
+}
+
+/// Filters applied to the system user database to build the user list
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsersConfig {
+    pub mode: UsersMode,
+    pub min_uid: u32,
+    pub max_uid: u32,
+    pub hidden_shells: Vec<PathBuf>,
+    pub hidden_users: Vec<String>,
+}
+
+impl Default for UsersConfig {
+    fn default() -> Self {
+        Self {
+            mode: UsersMode::List,
+            min_uid: 1000,
+            max_uid: 60000,
+            hidden_shells: [
+                "/bin/false", "/usr/bin/false",
+                "/sbin/nologin", "/usr/sbin/nologin", "/usr/bin/nologin",
+            ].into_iter().map(PathBuf::from).collect(),
+            hidden_users: Vec::new(),
+        }
+    }
+}
+
+/// Where the session desktop entries are read from
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionsConfig {
+    pub xsessions_dir: PathBuf,
+    pub wayland_sessions_dir: PathBuf,
+    /// Starts Wayland sessions on their own VT instead of the greeter's one,
+    /// the greeter staying up so that other users can log in meanwhile. X
+    /// sessions always use the greeter's X server, which ends the greeter
+    /// until they end
+    pub own_vt: bool,
+    /// Where the output of the session goes, relative to the user's home
+    /// unless absolute. The previous log is kept with a ".old" suffix
+    pub log_path: PathBuf,
+}
+
+impl Default for SessionsConfig {
+    fn default() -> Self {
+        Self {
+            xsessions_dir: PathBuf::from("/usr/share/xsessions"),
+            wayland_sessions_dir: PathBuf::from("/usr/share/wayland-sessions"),
+            own_vt: false,
+            log_path: PathBuf::from(".local/share/himmel/session.log"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct XServerConfig {
+    pub path: PathBuf,
+    /// ":<number>", or "auto" to use the first free display number
+    pub display: String,
+    /// "vt<number>", or "auto" to use the first free VT
+    pub vt: String,
+    /// Authority file given to the X server, holding the greeter's cookie. The
+    /// users' ones are written in the same directory
+    pub auth_path: PathBuf,
+}
+
+impl Default for XServerConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("/usr/lib/Xorg"),
+            display: String::from(":1"),
+            vt: String::from("vt01"),
+            auth_path: PathBuf::from("/run/himmel/xauthority"),
+        }
+    }
+}
+
+impl XServerConfig {
+    /// Number of the configured VT, None if it must be allocated, must only be
+    /// called on a validated config
+    pub fn fixed_vt(&self) -> Option<u32> {
+        if self.vt == "auto" {
+            None
+        }
+        else {
+            Some(self.vt.trim_start_matches("vt").parse().expect("Invalid VT"))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PamConfig {
+    pub service: String,
+    /// Service used to register the greeter itself with logind, if any
+    pub greeter_service: Option<String>,
+    pub greeter_user: String,
+    pub seat: String,
+}
+
+impl Default for PamConfig {
+    fn default() -> Self {
+        Self {
+            service: String::from("system-auth"),
+            greeter_service: None,
+            greeter_user: String::from("root"),
+            seat: String::from("seat0"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StateConfig {
+    pub path: PathBuf,
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("/var/lib/himmel/state"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Most verbose level written to the journal
+    pub level: log::LevelFilter,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: log::LevelFilter::Info,
+        }
+    }
+}
+
+/// Logs a user in without password when the greeter first starts
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AutologinConfig {
+    /// Autologin is disabled unless set, never allowed for root
+    pub user: Option<String>,
+    /// Id of the session ("x11/" or "wayland/" and the name of its desktop
+    /// file without extension, or "xinitrc"), the user's last session if not set
+    pub session: Option<String>,
+    /// Seconds of countdown during which any key cancels the autologin
+    pub timeout: u64,
+    /// PAM service authenticating the user without password
+    pub service: String,
+}
+
+impl Default for AutologinConfig {
+    fn default() -> Self {
+        Self {
+            user: None,
+            session: None,
+            timeout: 5,
+            service: String::from("himmel-autologin"),
+        }
+    }
+}
+
+impl Config {
+    /// Reads, parses and validates the config file at the given path
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Could not read config file {}", path.display()))?;
+        let config: Config = toml::from_str(&content)
+            .with_context(|| format!("Could not parse config file {}", path.display()))?;
+        config.validate()
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+        Ok(config)
+    }
+
+    /// Loads the config given with `--config`, or the default one. Only the
+    /// default file may be missing, every setting then has its default value
+    pub fn from_args(args: impl IntoIterator<Item = OsString>) -> anyhow::Result<Self> {
+        match Self::path_from_args(args)? {
+            Some(path) => Self::load(path),
+            None => Self::load_or_default(Path::new(DEFAULT_CONFIG_PATH)),
+        }
+    }
+
+    /// Like [Config::load], but a missing file gives the default config
+    fn load_or_default(path: &Path) -> anyhow::Result<Self> {
+        match fs::metadata(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                log::info!(phase = "config"; "No config file at {}, using the defaults", path.display());
+                Ok(Self::default())
+            }
+            _ => Self::load(path),
+        }
+    }
+
+    /// Returns the config path given with `--config`, if any
+    pub fn path_from_args(
+        args: impl IntoIterator<Item = OsString>
+    ) -> anyhow::Result<Option<PathBuf>> {
+        let mut path = None;
+        let mut args = args.into_iter().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == "--config" {
+                path = Some(args.next()
+                    .ok_or_else(|| anyhow!("Missing value after --config"))?
+                    .into());
+            }
+            else if let Some(value) = arg.to_str().and_then(|a| a.strip_prefix("--config=")) {
+                path = Some(value.into());
+            }
+        }
+        Ok(path)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        ensure!(self.login.pass_length > 0, "login.pass_length must be greater than 0");
+        ensure!(self.login.max_length > 0, "login.max_length must be greater than 0");
+        ensure!(
+            self.users.min_uid <= self.users.max_uid,
+            "users.min_uid ({}) must not be greater than users.max_uid ({})",
+            self.users.min_uid, self.users.max_uid
+        );
+
+        // Digits only, parse accepts a sign
+        let number = |n: &str| Some(n)
+            .filter(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+            .and_then(|n| n.parse::<u32>().ok());
+
+        let display_number = self.x_server.display.strip_prefix(':').and_then(number);
+        if display_number.is_none() && self.x_server.display != "auto" {
+            bail!(
+                "x_server.display must be \"auto\" or look like \":<number>\", got {:?}",
+                self.x_server.display
+            );
+        }
+        let vt_number = self.x_server.vt.strip_prefix("vt").and_then(number)
+            .filter(|&n| n >= 1);
+        if vt_number.is_none() && self.x_server.vt != "auto" {
+            bail!(
+                "x_server.vt must be \"auto\" or look like \"vt<number>\" with a number from 1, got {:?}",
+                self.x_server.vt
+            );
+        }
+        ensure!(
+            self.x_server.path.is_absolute(),
+            "x_server.path must be an absolute path, got {}", self.x_server.path.display()
+        );
+
+        ensure!(
+            self.x_server.auth_path.is_absolute(),
+            "x_server.auth_path must be an absolute path, got {}", self.x_server.auth_path.display()
+        );
+
+        ensure!(!self.pam.service.is_empty(), "pam.service must not be empty");
+        ensure!(
+            self.pam.greeter_service.as_ref().is_none_or(|s| !s.is_empty()),
+            "pam.greeter_service must not be empty"
+        );
+        ensure!(!self.pam.greeter_user.is_empty(), "pam.greeter_user must not be empty");
+        ensure!(!self.pam.seat.is_empty(), "pam.seat must not be empty");
+        ensure!(
+            self.state.path.is_absolute(),
+            "state.path must be an absolute path, got {}", self.state.path.display()
+        );
+        if let Some(user) = &self.autologin.user {
+            ensure!(!user.is_empty(), "autologin.user must not be empty");
+            ensure!(user != "root", "autologin.user must not be root");
+        }
+        ensure!(!self.autologin.service.is_empty(), "autologin.service must not be empty");
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> anyhow::Result<Config> {
+        let config: Config = toml::from_str(content)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(Config::default().validate().is_ok());
+        let config = parse("").unwrap();
+        assert_eq!(config.x_server.fixed_vt(), Some(1));
+        assert!(parse(include_str!("../config.example.toml")).is_ok());
+    }
+
+    #[test]
+    fn vt_and_display() {
+        let vt = |vt: &str| parse(&format!("[x_server]\nvt = {vt:?}\n"));
+        assert_eq!(vt("vt7").unwrap().x_server.fixed_vt(), Some(7));
+        assert_eq!(vt("auto").unwrap().x_server.fixed_vt(), None);
+        for invalid in ["vt0", "vt", "7", "vt+7", "vt-1", "vt99999999999"] {
+            assert!(vt(invalid).is_err(), "{invalid}");
+        }
+
+        let display = |display: &str| parse(&format!("[x_server]\ndisplay = {display:?}\n"));
+        assert!(display(":0").is_ok());
+        assert!(display("auto").is_ok());
+        for invalid in [":", "0", ":+1", ":99999999999", ":1.0"] {
+            assert!(display(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn invalid_values() {
+        assert!(parse("[login]\npass_length = 0\n").is_err());
+        assert!(parse("[users]\nmin_uid = 2000\nmax_uid = 1000\n").is_err());
+        assert!(parse("[x_server]\npath = \"Xorg\"\n").is_err());
+        assert!(parse("[pam]\ngreeter_service = \"\"\n").is_err());
+        assert!(parse("[autologin]\nuser = \"root\"\n").is_err());
+        assert!(parse("[autologin]\nuser = \"\"\n").is_err());
+        assert!(parse("[login]\nunknown = 1\n").is_err());
+    }
+
+    #[test]
+    fn config_path() {
+        let path = |args: &[&str]| Config::path_from_args(
+            ["himmel"].iter().chain(args).map(OsString::from)
+        );
+        assert_eq!(path(&[]).unwrap(), None);
+        assert_eq!(path(&["--greeter"]).unwrap(), None);
+        assert_eq!(path(&["--config", "/a.toml"]).unwrap(), Some(PathBuf::from("/a.toml")));
+        assert_eq!(path(&["--config=/b.toml", "--greeter"]).unwrap(), Some(PathBuf::from("/b.toml")));
+        assert!(path(&["--config"]).is_err());
+        // The program name is not an argument
+        let only_program = Config::path_from_args([OsString::from("--config=/c.toml")]);
+        assert_eq!(only_program.unwrap(), None);
+    }
+
+    #[test]
+    fn missing_file() {
+        let missing = Path::new("/nonexistent/himmel.toml");
+        assert!(Config::load_or_default(missing).is_ok());
+        assert!(Config::load(missing).is_err());
+        let args = ["himmel", "--config=/nonexistent/himmel.toml"].map(OsString::from);
+        assert!(Config::from_args(args).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+use crate::config::Config;
+use crate::pam_wrapper::{ Author, AuthError };
+
+use std::env;
+use std::io::{ self, Read };
+use std::process;
+
+/// Given to the worker process holding the greeter's session, with the VT
+pub const WORKER_ARG: &str = "--greeter-session";
+
+/// The greeter's logind session of class greeter. It is opened by a worker
+/// process, as gdm and lightdm do: logind refuses a session to a process
+/// already in one, so the greeter itself must stay out of it to open the
+/// user's session. Closed when dropped
+pub struct GreeterSession {
+    worker: process::Child,
+}
+
+impl GreeterSession {
+    /// None if no greeter service is configured or the worker can't be started
+    pub fn open(config: &Config, vt: u32) -> Option<Self> {
+        config.pam.greeter_service.as_ref()?;
+
+        // The same arguments, for --config
+        let worker = env::current_exe().and_then(|exe| {
+            process::Command::new(exe)
+                .args(env::args_os().skip(1))
+                .arg(format!("{WORKER_ARG}={vt}"))
+                .stdin(process::Stdio::piped())
+                .spawn()
+        });
+        match worker {
+            Ok(worker) => Some(Self { worker }),
+            Err(e) => {
+                log::error!(phase = "greeter"; "Could not start the greeter session worker: {e}");
+                None
+            }
+        }
+    }
+}
+
+impl Drop for GreeterSession {
+    /// Waits for the session to be closed
+    fn drop(&mut self) {
+        // The worker closes the session once its stdin is closed
+        drop(self.worker.stdin.take());
+        if let Err(e) = self.worker.wait() {
+            log::error!(phase = "greeter"; "Could not wait for the greeter session worker: {e}");
+        }
+    }
+}
+
+/// The VT of the greeter's session if this process is its worker
+pub fn worker_vt() -> Option<u32> {
+    env::args_os().find_map(|arg| {
+        arg.to_str()?
+            .strip_prefix(WORKER_ARG)?
+            .strip_prefix('=')?
+            .parse()
+            .ok()
+    })
+}
+
+/// Holds the session until the greeter closes stdin, or exits
+pub fn run_worker(config: &Config, vt: u32) -> ! {
+    let author = match open(config, vt) {
+        Ok(author) => author,
+        Err(e) => {
+            log::error!(phase = "greeter"; "Could not open the greeter session: {e}");
+            process::exit(1);
+        }
+    };
+    // Nothing is written, only returns once the pipe is closed
+    let _ = io::stdin().read_to_end(&mut Vec::new());
+    drop(author);
+    process::exit(0);
+}
+
+fn open(config: &Config, vt: u32) -> Result<Author, AuthError> {
+    let service = config.pam.greeter_service.as_ref()
+        .ok_or(AuthError::ServiceUnavailable)?;
+    let mut author = Author::new(service)?;
+    author
+        .set_username(config.pam.greeter_user.as_str())?
+        .set_logind_env(&config.pam.seat, vt, "greeter", "x11");
+    // No authentication, the greeter service is expected to allow its user
+    author.open_session()?;
+    Ok(author)
+}
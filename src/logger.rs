@@ -0,0 +1,152 @@
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+
+use log::{ kv, Level, LevelFilter, Log, Metadata, Record };
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_SOCKET: &str = "/dev/log";
+const IDENTIFIER: &str = "himmel";
+/// LOG_AUTHPRIV, as other login managers
+const SYSLOG_FACILITY: u8 = 10 << 3;
+
+/// Where the records are written, the first one available at startup
+enum Target {
+    /// systemd's native protocol, the fields are kept as journal fields
+    Journal(UnixDatagram),
+    /// RFC 3164 messages, the fields are appended to the message
+    Syslog(UnixDatagram),
+    Stderr,
+}
+
+struct Logger {
+    target: Target,
+}
+
+/// Installs the logger, records are kept up to the given level.
+///
+/// The fields given to the log macros (`username`, `session`, `phase`...) are
+/// sent along with the message. Passwords and prompt answers must never be
+/// given to them
+pub fn init(level: LevelFilter) {
+    let connect = |path| {
+        let socket = UnixDatagram::unbound().ok()?;
+        socket.connect(path).ok()?;
+        Some(socket)
+    };
+    let target = if let Some(socket) = connect(JOURNAL_SOCKET) {
+        Target::Journal(socket)
+    }
+    else if let Some(socket) = connect(SYSLOG_SOCKET) {
+        Target::Syslog(socket)
+    }
+    else {
+        Target::Stderr
+    };
+
+    // Only fails if a logger was already installed
+    if log::set_boxed_logger(Box::new(Logger { target })).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let sent = match &self.target {
+            Target::Journal(socket) => socket.send(&journal_entry(record)).is_ok(),
+            Target::Syslog(socket) => socket.send(syslog_line(record).as_bytes()).is_ok(),
+            Target::Stderr => false,
+        };
+        // Messages too big for a datagram or a restarted daemon
+        if !sent {
+            eprintln!("{}", stderr_line(record));
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+/// Priority of the record in syslog's terms
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Collects the key-values of a record
+struct Fields(Vec<(String, String)>);
+
+impl<'kvs> kv::VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+fn fields(record: &Record) -> Vec<(String, String)> {
+    let mut fields = Fields(Vec::new());
+    let _ = record.key_values().visit(&mut fields);
+    fields.0
+}
+
+/// Serializes the record in the journal's native format, the field names
+/// are uppercased as the journal requires
+fn journal_entry(record: &Record) -> Vec<u8> {
+    fn push_field(entry: &mut Vec<u8>, key: &str, value: &str) {
+        entry.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            // Binary-safe form: the length in little endian before the value
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        }
+        else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    }
+
+    let mut entry = Vec::new();
+    push_field(&mut entry, "MESSAGE", &record.args().to_string());
+    push_field(&mut entry, "PRIORITY", &severity(record.level()).to_string());
+    push_field(&mut entry, "SYSLOG_IDENTIFIER", IDENTIFIER);
+    push_field(&mut entry, "CODE_MODULE", record.target());
+    for (key, value) in fields(record) {
+        let key: String = key.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+            .collect();
+        push_field(&mut entry, &key, &value);
+    }
+    entry
+}
+
+fn syslog_line(record: &Record) -> String {
+    let priority = SYSLOG_FACILITY | severity(record.level());
+    let mut line = format!("<{priority}>{IDENTIFIER}[{}]: {}", process::id(), record.args());
+    for (key, value) in fields(record) {
+        let _ = write!(line, " {key}={value:?}");
+    }
+    line
+}
+
+fn stderr_line(record: &Record) -> String {
+    let mut line = format!("[{}] {}", record.level(), record.args());
+    for (key, value) in fields(record) {
+        let _ = write!(line, " {key}={value:?}");
+    }
+    line
+}
@@ -1,10 +1,26 @@
 mod app;
+mod config;
+mod greeter_session;
+mod logger;
 mod process_starts;
 mod pam_wrapper;
+mod session_env;
+mod sessions;
+mod state;
+mod supervisor;
+mod user_db;
+mod xauth;
 
-use pam_wrapper::Author;
+use config::{ Config, UsersMode, PasswordMode };
+use greeter_session::GreeterSession;
+use pam_wrapper::{ Author, AuthError, Conversation };
+use pam_sys::types::PamReturnCode;
+use sessions::{ Session, SessionKind };
+use state::State;
 
 use std::fmt;
+use std::sync::{ mpsc, Arc, Mutex };
+use std::time::Duration;
 
 use skulpin::{
     CoordinateSystemHelper,
@@ -17,18 +33,37 @@ use skia_safe::{
     Canvas, paint, Paint,
 };
 use winit::window::Fullscreen;
+use winit::event_loop::EventLoopProxy;
 
 pub enum UserEvent {
     LoginResult {
-        success: bool,
+        /// The author keeps the PAM session open
+        result: Result<Author, AuthError>,
         username: String,
         password: String,
-        author: Author,
+        session: Session,
+        vt: u32,
     },
     StartSession {
         username: String,
+        session: Session,
+        vt: u32,
         author: Author,
-    }
+    },
+    PamPrompt {
+        message: String,
+        echo: bool,
+        reply: mpsc::Sender<Option<String>>,
+    },
+    PamMessage {
+        message: String,
+        error: bool,
+    },
+    PasswordChangeRequired {
+        reply: mpsc::Sender<Option<app::PasswordChange>>,
+    },
+    /// The autologin countdown is over
+    AutologinTimeout,
 }
 
 impl fmt::Debug for UserEvent {
@@ -38,14 +73,231 @@ impl fmt::Debug for UserEvent {
     }
 }
 
+const PASSWORD_CHANGE_ATTEMPTS: usize = 3;
+
+/// Authenticates and opens the PAM session, asking the app for a new password
+/// if the current one expired. The greeter's session is closed once the user
+/// is authenticated, so that only the user's one is left on the VT
+fn login(author: &mut Author, context: &LoginContext) -> Result<(), AuthError> {
+    let proxy = &context.proxy;
+    // The error shown to the user hides the reason of some failures
+    let authenticated = author.authenticate().map_err(|e| {
+        log::info!(phase = "login"; "Authentication failed: {e:?}");
+        e
+    });
+    match authenticated {
+        Err(PamReturnCode::NEW_AUTHTOK_REQD) => {
+            let mut result = Err(PamReturnCode::NEW_AUTHTOK_REQD);
+            for _ in 0..PASSWORD_CHANGE_ATTEMPTS {
+                let (reply, answer) = mpsc::channel();
+                proxy.send_event(UserEvent::PasswordChangeRequired { reply })
+                    .map_err(|_| PamReturnCode::CONV_ERR)?;
+                let change = answer.recv().ok().flatten()
+                    .ok_or(PamReturnCode::CONV_ERR)?;
+
+                result = author.change_expired_authtok(change.current, change.new);
+                if result.is_ok() {
+                    break;
+                }
+            }
+            result?;
+        }
+        result => result?,
+    }
+
+    drop(context.greeter_session.lock().unwrap().take());
+    if let Err(e) = author.open_session() {
+        *context.greeter_session.lock().unwrap() = GreeterSession::open(&context.config, context.greeter_vt);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// What the login threads need besides the credentials
+#[derive(Clone)]
+struct LoginContext {
+    proxy: EventLoopProxy<UserEvent>,
+    config: Config,
+    greeter_vt: u32,
+    /// Closed when a user logs in
+    greeter_session: Arc<Mutex<Option<GreeterSession>>>,
+}
+
+impl LoginContext {
+    /// Authenticates with the PAM service and opens the session in another
+    /// thread, the result is sent as a LoginResult event. Without password,
+    /// the service's prompts are all answered by the user
+    fn spawn_login(&self, service: String, username: String, password: Option<String>, session: Session) {
+        supervisor::report_login_started();
+        let context = self.clone();
+        std::thread::spawn(move || {
+            let vt = match session.kind {
+                SessionKind::Wayland if context.config.sessions.own_vt => {
+                    process_starts::allocate_vt().unwrap_or_else(|e| {
+                        log::warn!(phase = "login"; "Could not allocate a VT for the session: {e}");
+                        context.greeter_vt
+                    })
+                }
+                _ => context.greeter_vt,
+            };
+
+            let result = (|| -> Result<Author, AuthError> {
+                let mut author = Author::new(&service)?;
+                author
+                    .set_username(username.as_str())?
+                    .set_conversation(UiConversation { proxy: context.proxy.clone() })
+                    .set_logind_env(&context.config.pam.seat, vt, "user", session.kind.session_type());
+                if let Some(password) = &password {
+                    author.set_password(password.as_str())?;
+                }
+                login(&mut author, &context)?;
+                Ok(author)
+            })();
+
+            let sent = context.proxy.send_event(UserEvent::LoginResult {
+                result,
+                username,
+                password: password.unwrap_or_default(),
+                session, vt,
+            });
+            // Only once the event loop is gone
+            if sent.is_err() {
+                log::error!(phase = "login"; "Could not send the login result");
+            }
+        });
+    }
+}
+
+/// Forwards the PAM conversation to the app, the PAM thread is blocked until
+/// the user answers the prompts
+struct UiConversation {
+    proxy: EventLoopProxy<UserEvent>,
+}
+
+impl Conversation for UiConversation {
+    fn prompt(&mut self, message: &str, echo: bool) -> Option<String> {
+        let (reply, answer) = mpsc::channel();
+        self.proxy.send_event(UserEvent::PamPrompt {
+            message: message.to_string(),
+            echo,
+            reply,
+        }).ok()?;
+        answer.recv().ok().flatten()
+    }
+
+    fn info(&mut self, message: &str) {
+        let _ = self.proxy.send_event(UserEvent::PamMessage {
+            message: message.to_string(),
+            error: false,
+        });
+    }
+
+    fn error(&mut self, message: &str) {
+        let _ = self.proxy.send_event(UserEvent::PamMessage {
+            message: message.to_string(),
+            error: true,
+        });
+    }
+}
+
+/// The user to log in automatically, if autologin is configured and no greeter
+/// started a login since boot. Never root
+fn autologin_user(config: &Config, sessions: &[Session]) -> Option<String> {
+    let username = config.autologin.user.as_ref()?;
+    if !std::env::args_os().any(|a| a == supervisor::AUTOLOGIN_ARG) {
+        return None;
+    }
+
+    match users::get_user_by_name(username) {
+        Some(user) if user.uid() == 0 => {
+            log::error!(username = username.as_str(), phase = "autologin"; "Refusing to log root in automatically");
+            return None;
+        }
+        Some(_) => (),
+        None => {
+            log::error!(username = username.as_str(), phase = "autologin"; "The autologin user does not exist");
+            return None;
+        }
+    }
+    if let Some(id) = &config.autologin.session {
+        if !sessions.iter().any(|s| &s.id == id) {
+            log::warn!(
+                username = username.as_str(), session = id.as_str(), phase = "autologin";
+                "Unknown autologin session, using the last one of the user"
+            );
+        }
+    }
+    Some(username.clone())
+}
+
+/// Waits for the user's session to end, logging how it ended
+fn wait_session(mut child: std::process::Child) {
+    match child.wait() {
+        Ok(status) if !status.success() => log::warn!(phase = "session"; "Session exited with {status}"),
+        Ok(_) => log::info!(phase = "session"; "Session ended"),
+        Err(e) => log::error!(phase = "session"; "Could not wait for the session: {e}"),
+    }
+}
+
 fn main() {
+    logger::init(log::LevelFilter::Info);
+    let config = match Config::from_args(std::env::args_os()) {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!(phase = "config"; "Error while loading the configuration: {e:?}");
+            std::process::exit(1);
+        }
+    };
+    log::set_max_level(config.log.level);
+
+    if let Some(vt) = greeter_session::worker_vt() {
+        greeter_session::run_worker(&config, vt);
+    }
+
+    // The greeter process exits once the user's session ended, so it is
+    // restarted by the supervisor
+    let is_greeter = std::env::args_os().any(|a| a == supervisor::GREETER_ARG);
+    if cfg!(not(feature="debug")) && !is_greeter {
+        supervisor::supervise();
+    }
+    supervisor::take_login_pipe();
+
+    let user_source = match config.users.mode {
+        UsersMode::List => {
+            let users = user_db::human_users(&config.users);
+            if users.is_empty() {
+                log::error!(phase = "config"; "No user matches the [users] filters of the configuration");
+                std::process::exit(1);
+            }
+            app::UserSource::List(users)
+        }
+        UsersMode::Typed => app::UserSource::Typed,
+    };
+
+    let greeter_vt = match process_starts::greeter_vt(&config.x_server) {
+        Ok(vt) => vt,
+        Err(e) => {
+            log::error!(phase = "x_server"; "Could not find a free VT: {e}");
+            std::process::exit(1);
+        }
+    };
+
     if cfg!(not(feature="debug")) && std::env::var("DISPLAY").is_err() {
-        process_starts::start_x_server();
+        // Nothing can be shown without it, the supervisor retries
+        if let Err(e) = process_starts::start_x_server(&config.x_server, greeter_vt) {
+            log::error!(phase = "x_server"; "Could not start the X server: {e:?}");
+            std::process::exit(1);
+        }
     }
     if cfg!(not(feature="debug")) {
-        std::process::Command::new("/bin/bash")
+        let status = std::process::Command::new("/bin/bash")
             .arg("-c").arg("xlayoutdisplay -m --dpi 96")
-            .spawn().unwrap().wait().unwrap();
+            .status();
+        match status {
+            Ok(status) if status.success() => (),
+            Ok(status) => log::warn!(phase = "x_server"; "xlayoutdisplay exited with {status}"),
+            Err(e) => log::warn!(phase = "x_server"; "Could not run xlayoutdisplay: {e}"),
+        }
     }
 
     // Create the winit event loop
@@ -88,34 +340,64 @@ fn main() {
 
     // Check if there were error setting up vulkan
     if let Err(e) = renderer {
-        println!("Error during renderer construction: {:?}", e);
+        log::error!(phase = "greeter"; "Error during renderer construction: {:?}", e);
         return;
     }
     let mut renderer = renderer.unwrap();
 
+    let greeter_session = if cfg!(not(feature="debug")) {
+        GreeterSession::open(&config, greeter_vt)
+    }
+    else {
+        None
+    };
+
+    let login_context = LoginContext {
+        proxy: event_loop_proxy.clone(),
+        config: config.clone(),
+        greeter_vt,
+        greeter_session: Arc::new(Mutex::new(greeter_session)),
+    };
     let login_callback = {
-        let proxy = event_loop_proxy.clone();
-        move |username: String, password: String| {
-            std::thread::spawn({
-                let proxy = proxy.clone();
-                move || {
-                    let mut author = Author::new();
-                    author
-                        .set_username(username.as_str())
-                        .set_password(password.as_str());
-                    
-                    proxy.send_event(UserEvent::LoginResult {
-                        success: author.open_session().is_ok(),
-                        username, password,
-                        author,
-                    }).expect("Could not send login event");
-                }
-            });
+        let login_context = login_context.clone();
+        let pam_service = config.pam.service.clone();
+        move |username: String, password: String, session: Session| {
+            login_context.spawn_login(pam_service.clone(), username, Some(password), session);
         }
     };
 
-    let mut app = app::App::new(login_callback, "malo", 4);
+    let password_mode = match config.login.password_mode {
+        PasswordMode::Fixed => app::PasswordMode::Fixed {
+            length: config.login.pass_length,
+        },
+        PasswordMode::Variable => app::PasswordMode::Variable {
+            boxes: config.login.pass_length,
+            max_length: config.login.max_length,
+        },
+    };
+    let sessions = sessions::available_sessions(&config.sessions);
+    let autologin_user = autologin_user(&config, &sessions);
+    let mut app = app::App::new(
+        login_callback,
+        user_source,
+        password_mode,
+        sessions,
+    );
+    let mut state = State::load(&config.state.path);
+    app.restore_state(&state);
+
+    if let Some(username) = autologin_user {
+        let timeout = Duration::from_secs(config.autologin.timeout);
+        app.start_autologin(username, config.autologin.session.as_deref(), timeout);
+        let proxy = event_loop_proxy.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(timeout);
+            let _ = proxy.send_event(UserEvent::AutologinTimeout);
+        });
+    }
     let mut do_on_quit: Vec<Box<dyn FnOnce() -> ()>> = Vec::new();
+    // Sessions running on their own VT alongside the greeter
+    let mut running_sessions: Vec<std::thread::JoinHandle<()>> = Vec::new();
 
     let mut window = Some(window);
     event_loop.run(move |event, _start_x_serverwindow_target, control_flow| {
@@ -123,7 +405,7 @@ fn main() {
             winit::event::Event::WindowEvent {
                 event: winit::event::WindowEvent::CloseRequested,
                 ..
-            } => *control_flow = winit::event_loop::ControlFlow::Exit,
+            } if cfg!(feature = "debug") => *control_flow = winit::event_loop::ControlFlow::Exit,
 
             winit::event::Event::WindowEvent {
                 event:
@@ -136,7 +418,7 @@ fn main() {
                         ..
                     },
                 ..
-            } => *control_flow = winit::event_loop::ControlFlow::Exit,
+            } if cfg!(feature = "debug") => *control_flow = winit::event_loop::ControlFlow::Exit,
 
             winit::event::Event::WindowEvent { event, .. } => {
                 if let Some(event) = event.to_static() {
@@ -148,32 +430,140 @@ fn main() {
                 w.request_redraw();
             }
 
-            winit::event::Event::UserEvent(UserEvent::LoginResult{ success, author, username, .. }) => {
-                let wait_duration = app.login_result(success);
+            winit::event::Event::UserEvent(UserEvent::LoginResult{ result, username, session, vt, .. }) => {
+                let (result, author) = match result {
+                    Ok(author) => (Ok(()), Some(author)),
+                    Err(e) => (Err(e), None),
+                };
+                // Never the password
+                match &result {
+                    Ok(()) => log::info!(
+                        username = username.as_str(), session = session.id.as_str(), phase = "login";
+                        "Login succeeded"
+                    ),
+                    Err(e) => log::warn!(
+                        username = username.as_str(), session = session.id.as_str(), phase = "login";
+                        "Login failed: {e}"
+                    ),
+                }
+                let wait_duration = app.login_result(result);
+                app.store_failed_logins(&mut state);
+                if let Err(e) = state.save(&config.state.path) {
+                    log::warn!("Could not save the state: {e:?}");
+                }
+                // The app goes back to the password input on failure
+                let author = match author {
+                    Some(author) => author,
+                    None => return,
+                };
                 std::thread::spawn({
                     let proxy = event_loop_proxy.clone();
                     move || {
                         std::thread::sleep(wait_duration);
-                        proxy.send_event(UserEvent::StartSession { username, author }).unwrap();
+                        proxy.send_event(UserEvent::StartSession { username, session, vt, author }).unwrap();
                     }
                 });
             }
 
-            winit::event::Event::UserEvent(UserEvent::StartSession { username, author }) => {
+            winit::event::Event::UserEvent(UserEvent::StartSession { username, session, vt, author }) => {
+                // On its own VT, a Wayland session runs while the greeter stays
+                // up for other users
+                let alongside = session.kind == SessionKind::Wayland && vt != greeter_vt;
                 if cfg!(not(feature = "debug")) {
-                    let mut child = process_starts::start_session(author, username);
-                    do_on_quit.push(Box::new(move || {
-                        child.wait().unwrap();
-                    }));
+                    match session.kind {
+                        // The compositor needs the VT of the greeter's X server,
+                        // which can only be stopped once the event loop is done with it.
+                        // A failure then restarts the greeter
+                        SessionKind::Wayland if !alongside => do_on_quit.push(Box::new({
+                            let username = username.clone();
+                            let session = session.clone();
+                            let log_path = config.sessions.log_path.clone();
+                            move || {
+                                process_starts::stop_x_server();
+                                match process_starts::start_session(&author, username, &session, vt, &log_path) {
+                                    Ok(child) => wait_session(child),
+                                    // No X server left to show it, the supervisor does
+                                    Err(e) => {
+                                        log::error!(phase = "session"; "Could not start the session: {e:?}");
+                                        drop(author);
+                                        std::process::exit(process_starts::SESSION_FAILURE_EXIT_CODE);
+                                    }
+                                }
+                                // Closes the PAM session
+                                drop(author);
+                            }
+                        })),
+                        SessionKind::Wayland | SessionKind::X11 | SessionKind::Xinitrc => {
+                            let log_path = &config.sessions.log_path;
+                            match process_starts::start_session(&author, username.clone(), &session, vt, log_path) {
+                                Ok(child) if alongside => running_sessions.push(std::thread::spawn(move || {
+                                    wait_session(child);
+                                    // Closes the PAM session
+                                    drop(author);
+                                    if let Err(e) = process_starts::return_to_greeter_vt(vt, greeter_vt) {
+                                        log::warn!(phase = "session"; "Could not go back to the greeter's VT: {e}");
+                                    }
+                                })),
+                                Ok(child) => do_on_quit.push(Box::new(move || {
+                                    wait_session(child);
+                                    // Closes the PAM session
+                                    drop(author);
+                                })),
+                                // The greeter stays up so that the user can retry, the
+                                // author is dropped which closes the PAM session
+                                Err(e) => {
+                                    log::error!(phase = "session"; "Could not start the session: {e:?}");
+                                    app.session_error(format!("{e:#}"));
+                                    drop(author);
+                                    *login_context.greeter_session.lock().unwrap() =
+                                        GreeterSession::open(&config, greeter_vt);
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                }
+
+                state.record_login(&username, &session.id);
+                if let Err(e) = state.save(&config.state.path) {
+                    log::warn!("Could not save the state: {e:?}");
                 }
 
+                if alongside {
+                    app.session_started();
+                    *login_context.greeter_session.lock().unwrap() =
+                        GreeterSession::open(&config, greeter_vt);
+                    return;
+                }
                 drop(window.take());
                 *control_flow = winit::event_loop::ControlFlow::Exit;
             }
 
+            winit::event::Event::UserEvent(UserEvent::PamPrompt { message, echo, reply }) => {
+                app.pam_prompt(message, echo, reply);
+            }
+
+            winit::event::Event::UserEvent(UserEvent::PamMessage { message, error }) => {
+                app.pam_message(message, error);
+            }
+
+            winit::event::Event::UserEvent(UserEvent::PasswordChangeRequired { reply }) => {
+                app.password_change(reply);
+            }
+
+            // Nothing to do if the user cancelled the countdown
+            winit::event::Event::UserEvent(UserEvent::AutologinTimeout) => {
+                if let Some((username, session)) = app.autologin_timeout() {
+                    log::info!(
+                        username = username.as_str(), session = session.id.as_str(), phase = "autologin";
+                        "Logging in automatically"
+                    );
+                    login_context.spawn_login(config.autologin.service.clone(), username, None, session);
+                }
+            }
+
             winit::event::Event::RedrawRequested(_window_id) => if let Some(w) = &window {
                 let window_size = w.inner_size();
-                println!("{window_size:?}");
                 let window_extents = RafxExtents2D {
                     width: window_size.width,
                     height: window_size.height,
@@ -186,7 +576,7 @@ fn main() {
                         app.frame(canvas, coordinate_system_helper);
                     },
                 ) {
-                    println!("Error during draw: {:?}", e);
+                    log::error!(phase = "greeter"; "Error during draw: {:?}", e);
                 }
             }
 
@@ -194,6 +584,10 @@ fn main() {
                 for f in do_on_quit.drain(..) {
                     f();
                 }
+                // Their PAM sessions are closed by these threads
+                for session in running_sessions.drain(..) {
+                    let _ = session.join();
+                }
 
                 process_starts::stop_x_server();
             }
@@ -1,8 +1,10 @@
 use std::ptr::null_mut;
 use std::ffi::{ c_void, CStr, CString, OsStr };
-use std::{ mem, env };
+use std::{ fmt, mem };
 use libc::{ c_int, calloc, size_t, strdup, free };
 
+use anyhow::Context;
+
 use pam_sys::types::{
     PamConversation,
     PamMessage,
@@ -10,6 +12,7 @@ use pam_sys::types::{
     PamHandle,
     PamReturnCode,
     PamMessageStyle,
+    PamItemType,
     PamFlag,
 };
 use pam_sys::wrapped as pms;
@@ -25,6 +28,123 @@ fn cprc(code: PamReturnCode) -> Result<(), PamReturnCode> {
     }
 }
 
+/// Why a login failed, in terms that can be shown to the user
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthError {
+    /// Also for unknown users, which must not be told apart from wrong
+    /// passwords
+    WrongPassword,
+    AccountExpired,
+    AccountLocked,
+    PasswordExpired,
+    PasswordChangeFailed,
+    MaxTries,
+    ServiceUnavailable,
+    SessionFailed,
+    /// A prompt was not answered
+    Cancelled,
+    Other(PamReturnCode),
+}
+
+impl From<PamReturnCode> for AuthError {
+    fn from(code: PamReturnCode) -> Self {
+        use PamReturnCode as C;
+        match code {
+            C::AUTH_ERR | C::USER_UNKNOWN => Self::WrongPassword,
+            C::ACCT_EXPIRED => Self::AccountExpired,
+            C::PERM_DENIED => Self::AccountLocked,
+            C::NEW_AUTHTOK_REQD | C::AUTHTOK_EXPIRED => Self::PasswordExpired,
+            C::AUTHTOK_ERR | C::AUTHTOK_RECOVERY_ERR | C::AUTHTOK_LOCK_BUSY
+            | C::AUTHTOK_DISABLE_AGING | C::TRY_AGAIN => Self::PasswordChangeFailed,
+            C::MAXTRIES => Self::MaxTries,
+            C::AUTHINFO_UNAVAIL | C::SERVICE_ERR | C::SYSTEM_ERR | C::OPEN_ERR
+            | C::SYMBOL_ERR | C::MODULE_UNKNOWN | C::BUF_ERR => Self::ServiceUnavailable,
+            C::SESSION_ERR | C::CRED_ERR | C::CRED_UNAVAIL | C::CRED_EXPIRED
+            | C::CRED_INSUFFICIENT => Self::SessionFailed,
+            C::CONV_ERR | C::ABORT => Self::Cancelled,
+            code => Self::Other(code),
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WrongPassword => write!(f, "Wrong password"),
+            Self::AccountExpired => write!(f, "Account expired"),
+            Self::AccountLocked => write!(f, "Account locked or access denied"),
+            Self::PasswordExpired => write!(f, "Password expired"),
+            Self::PasswordChangeFailed => write!(f, "Could not change the password"),
+            Self::MaxTries => write!(f, "Too many attempts"),
+            Self::ServiceUnavailable => write!(f, "Authentication service unavailable"),
+            Self::SessionFailed => write!(f, "Could not open the session"),
+            Self::Cancelled => write!(f, "Login cancelled"),
+            Self::Other(code) => write!(f, "Login failed ({code:?})"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Answers the PAM messages that can't be answered with the username and
+/// password given to the [Author]
+pub trait Conversation: Send {
+    /// Returns None to cancel the conversation
+    fn prompt(&mut self, message: &str, echo: bool) -> Option<String>;
+    fn info(&mut self, message: &str);
+    fn error(&mut self, message: &str);
+}
+
+struct ConvData {
+    username: CString,
+    password: CString,
+    /// The password only answers the first hidden prompt, once one was given
+    password_used: bool,
+    /// Current and new password answering the prompts of pam_chauthtok, by
+    /// order since the prompts may be localized: the current one answers the
+    /// first hidden prompt, the new one all the others
+    authtok_change: Option<(Option<CString>, CString)>,
+    conversation: Option<Box<dyn Conversation>>,
+}
+
+impl ConvData {
+    fn answer(&mut self, style: PamMessageStyle, msg: &str) -> Result<Option<CString>, PamReturnCode> {
+        match style {
+            PamMessageStyle::PROMPT_ECHO_OFF if self.authtok_change.is_some() => {
+                let (current, new) = self.authtok_change.as_mut().unwrap();
+                Ok(Some(current.take().unwrap_or_else(|| new.clone())))
+            }
+            PamMessageStyle::PROMPT_ECHO_OFF if !self.password_used => {
+                self.password_used = true;
+                Ok(Some(self.password.clone()))
+            }
+            PamMessageStyle::PROMPT_ECHO_ON | PamMessageStyle::PROMPT_ECHO_OFF => {
+                let echo = matches!(style, PamMessageStyle::PROMPT_ECHO_ON);
+                let answer = self.conversation.as_mut()
+                    .and_then(|c| c.prompt(msg, echo))
+                    .ok_or(PamReturnCode::CONV_ERR)?;
+                CString::new(answer)
+                    .map(Some)
+                    .map_err(|_| PamReturnCode::CONV_ERR)
+            }
+            PamMessageStyle::TEXT_INFO => {
+                log::info!(phase = "pam"; "{msg}");
+                if let Some(c) = self.conversation.as_mut() {
+                    c.info(msg);
+                }
+                Ok(None)
+            }
+            PamMessageStyle::ERROR_MSG => {
+                log::warn!(phase = "pam"; "{msg}");
+                if let Some(c) = self.conversation.as_mut() {
+                    c.error(msg);
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
 pub(crate) extern "C" fn pam_conv(
     num_msg: c_int,
     in_msg:  *mut *mut PamMessage,
@@ -35,30 +155,19 @@ pub(crate) extern "C" fn pam_conv(
     if resp_ptr.is_null() {
         return PamReturnCode::BUF_ERR as c_int;
     }
-    let resp = &mut *resp_ptr;
 
-    let (username, password) = &*(appdata_ptr as *const (CString, CString));
+    let data = &mut *(appdata_ptr as *mut ConvData);
     let mut result: PamReturnCode = PamReturnCode::SUCCESS;
 
     for i in 0..num_msg as isize {
         let current_msg  = &mut **in_msg.offset(i);
-        let msg = CStr::from_ptr(current_msg.msg);
+        let resp = &mut *resp_ptr.offset(i);
+        let msg = CStr::from_ptr(current_msg.msg).to_string_lossy();
 
-        // match on msg_style
-        match PamMessageStyle::from(current_msg.msg_style) {
-            PamMessageStyle::PROMPT_ECHO_ON => {
-                resp.resp = strdup(username.as_ptr());
-            }
-            PamMessageStyle::PROMPT_ECHO_OFF => {
-                resp.resp = strdup(password.as_ptr());
-            }
-            PamMessageStyle::TEXT_INFO => {
-                println!("INFO: {}", msg.to_str().unwrap());
-            }
-            PamMessageStyle::ERROR_MSG => {
-                println!("ERROR: {}", msg.to_str().unwrap());
-                result = PamReturnCode::CONV_ERR;
-            }
+        match data.answer(PamMessageStyle::from(current_msg.msg_style), &msg) {
+            Ok(Some(answer)) => resp.resp = strdup(answer.as_ptr()),
+            Ok(None) => (),
+            Err(e) => result = e,
         }
 
         if result != PamReturnCode::SUCCESS {
@@ -68,6 +177,9 @@ pub(crate) extern "C" fn pam_conv(
 
     // free allocated memory if an error occured
     if result != PamReturnCode::SUCCESS {
+        for i in 0..num_msg as isize {
+            free((*resp_ptr.offset(i)).resp as *mut c_void);
+        }
         free(resp_ptr as *mut c_void);
     } else {
         *out_resp = resp_ptr;
@@ -76,67 +188,284 @@ pub(crate) extern "C" fn pam_conv(
     result as c_int
 } }
 
+/// A PAM transaction, the phases that succeeded are unwound when dropped
 pub struct Author {
     handle: *mut PamHandle,
-    data: Box<(CString, CString)>,
+    data: Box<ConvData>,
+    /// Given to pam_end
+    last_status: PamReturnCode,
+    cred_established: bool,
+    session_opened: bool,
 }
 
 impl Author {
-    pub fn new() -> Self {
+    /// Fails if the service can't be loaded
+    pub fn new(service: &str) -> Result<Self, AuthError> {
         let mut handle = null_mut();
-        let mut data = Box::new((
-            CString::new("").unwrap(),
-            CString::new("").unwrap(),
-        ));
+        let mut data = Box::new(ConvData {
+            username: CString::new("").unwrap(),
+            password: CString::new("").unwrap(),
+            password_used: true,
+            authtok_change: None,
+            conversation: None,
+        });
 
-        pms::start("system-auth", None, &PamConversation {
+        let last_status = pms::start(service, None, &PamConversation {
             conv: Some(pam_conv),
             data_ptr: (&mut *data) as *mut _ as *mut c_void,
         }, &mut handle);
-        
-        Self { handle, data }
+        if handle.is_null() {
+            return Err(AuthError::ServiceUnavailable);
+        }
+        cprc(last_status).map_err(|e| {
+            log::error!(phase = "pam"; "Could not start the PAM transaction: {e:?}");
+            AuthError::ServiceUnavailable
+        })?;
+
+        Ok(Self {
+            handle, data,
+            last_status,
+            cred_established: false,
+            session_opened: false,
+        })
     }
 
-    pub fn set_username(&mut self, username: impl Into<Vec<u8>>) -> &mut Self {
-        self.data.0 = CString::new(username.into()).expect("CString::new failed");
-        self
+    /// Remembers the status for pam_end
+    fn track(&mut self, code: PamReturnCode) -> Result<(), PamReturnCode> {
+        self.last_status = code;
+        cprc(code)
+    }
+
+    /// A username with a nul byte can't exist, it fails as a wrong password
+    pub fn set_username(&mut self, username: impl Into<Vec<u8>>) -> Result<&mut Self, AuthError> {
+        self.data.username = CString::new(username.into()).map_err(|_| AuthError::WrongPassword)?;
+        // So that modules don't have to prompt for it
+        let user_item = unsafe { &*(self.data.username.as_ptr() as *const c_void) };
+        let code = pms::set_item(unsafe { &mut *self.handle }, PamItemType::USER, user_item);
+        self.track(code).map_err(|e| {
+            log::error!(phase = "pam"; "Could not set the PAM user: {e:?}");
+            AuthError::ServiceUnavailable
+        })?;
+        Ok(self)
+    }
+
+    pub fn set_password(&mut self, password: impl Into<Vec<u8>>) -> Result<&mut Self, AuthError> {
+        self.data.password = CString::new(password.into()).map_err(|_| AuthError::WrongPassword)?;
+        self.data.password_used = false;
+        Ok(self)
     }
 
-    pub fn set_password(&mut self, password: impl Into<Vec<u8>>) -> &mut Self {
-        self.data.1 = CString::new(password.into()).expect("CString::new failed");
+    /// Used for the prompts and messages the username and password can't answer
+    pub fn set_conversation(&mut self, conversation: impl Conversation + 'static) -> &mut Self {
+        self.data.conversation = Some(Box::new(conversation));
         self
     }
 
+    /// Checks the credentials and the account validity, fails with
+    /// NEW_AUTHTOK_REQD if the password must be changed with
+    /// [Author::change_expired_authtok] before opening the session
+    pub fn authenticate(&mut self) -> Result<(), PamReturnCode> {
+        let handle = unsafe { &mut *self.handle };
+        self.track(pms::authenticate(handle, PamFlag::NONE))?;
+        self.track(pms::acct_mgmt(   handle, PamFlag::NONE))?;
+        Ok(())
+    }
+
+    pub fn change_expired_authtok(
+        &mut self,
+        current: impl Into<Vec<u8>>,
+        new: impl Into<Vec<u8>>,
+    ) -> Result<(), PamReturnCode> {
+        let current = CString::new(current.into()).map_err(|_| PamReturnCode::AUTHTOK_ERR)?;
+        let new = CString::new(new.into()).map_err(|_| PamReturnCode::AUTHTOK_ERR)?;
+        self.data.authtok_change = Some((Some(current), new));
+        let handle = unsafe { &mut *self.handle };
+        let result = self.track(pms::chauthtok(handle, PamFlag::CHANGE_EXPIRED_AUTHTOK));
+        self.data.authtok_change = None;
+        result
+    }
+
+    /// Must be called after a successful authentication
     pub fn open_session(&mut self) -> Result<(), PamReturnCode> {
         let handle = unsafe { &mut *self.handle };
-        cprc(pms::authenticate(handle, PamFlag::NONE))?;
-        cprc(pms::acct_mgmt(   handle, PamFlag::NONE))?;
-        cprc(pms::setcred(     handle, PamFlag::ESTABLISH_CRED))?;
-        let session_r = cprc(pms::open_session(handle, PamFlag::NONE));
-        match session_r {
-            Ok(()) => (),
-            Err(e) => {
-                cprc(pms::setcred(handle, PamFlag::DELETE_CRED))?;
-                return Err(e);
-            },
-        }
+        self.track(pms::setcred(     handle, PamFlag::ESTABLISH_CRED))?;
+        self.cred_established = true;
+        self.track(pms::open_session(handle, PamFlag::NONE))?;
+        self.session_opened = true;
 
         Ok(())
     }
 
+    /// Sets the variables pam_systemd uses to register the session with
+    /// logind, must be called before [Author::open_session]
+    pub fn set_logind_env(
+        &mut self,
+        seat: &str,
+        vt: u32,
+        class: &str,
+        session_type: &str,
+    ) -> &mut Self {
+        let vt = vt.to_string();
+        let vars = [
+            ("XDG_SEAT", seat),
+            ("XDG_VTNR", vt.as_str()),
+            ("XDG_SESSION_CLASS", class),
+            ("XDG_SESSION_TYPE", session_type),
+        ];
+        // The session still works without logind, only badly integrated
+        for (key, value) in vars {
+            if let Err(e) = self.put_env(key, value) {
+                log::warn!(phase = "pam"; "{e:#}");
+            }
+        }
+        self
+    }
+
+    /// Sets a variable of the PAM environment, which only ends up in the
+    /// session through [Author::env_list]
     pub fn put_env<'a>(
         &mut self,
         key: impl AsRef<OsStr>,
         value: impl AsRef<OsStr>,
-    ) {
-        let key = key.as_ref();
-        let value = value.as_ref();
-        env::set_var(key, value);
-        let key = key.to_string_lossy();
-        let value = value.to_string_lossy();
-        cprc(pms::putenv(unsafe { &mut *self.handle }, &format!("{key}={value}"))).unwrap();
+    ) -> anyhow::Result<()> {
+        let key = key.as_ref().to_string_lossy();
+        let value = value.as_ref().to_string_lossy();
+        cprc(pms::putenv(unsafe { &mut *self.handle }, &format!("{key}={value}")))
+            .map_err(AuthError::from)
+            .with_context(|| format!("Could not set {key} in the PAM environment"))
+    }
+
+    /// The PAM environment, with the variables set by the PAM modules
+    /// (XDG_RUNTIME_DIR, XDG_SESSION_ID...) and through [Author::put_env]
+    pub fn env_list(&self) -> Vec<(String, String)> {
+        let mut vars = Vec::new();
+        let list = unsafe { pam_sys::raw::pam_getenvlist(self.handle) };
+        if list.is_null() {
+            return vars;
+        }
+
+        // The list and its entries are ours to free
+        unsafe {
+            let mut i = 0;
+            while !(*list.add(i)).is_null() {
+                let entry = *list.add(i);
+                if let Some((key, value)) = CStr::from_ptr(entry).to_string_lossy().split_once('=') {
+                    vars.push((key.to_string(), value.to_string()));
+                }
+                free(entry as *mut c_void);
+                i += 1;
+            }
+            free(list as *mut c_void);
+        }
+        vars
+    }
+}
+
+impl Drop for Author {
+    fn drop(&mut self) {
+        if self.handle.is_null() {
+            return;
+        }
+        let handle = unsafe { &mut *self.handle };
+
+        if self.session_opened {
+            if let Err(e) = cprc(pms::close_session(handle, PamFlag::NONE)) {
+                log::error!(phase = "pam"; "Could not close the PAM session: {e:?}");
+            }
+        }
+        if self.cred_established {
+            if let Err(e) = cprc(pms::setcred(handle, PamFlag::DELETE_CRED)) {
+                log::error!(phase = "pam"; "Could not delete the PAM credentials: {e:?}");
+            }
+        }
+        pms::end(handle, self.last_status);
     }
 }
 
 unsafe impl Send for Author {}
 unsafe impl Sync for Author {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{ Arc, Mutex };
+
+    /// Answers the prompts with their message, records everything
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Conversation for Recorder {
+        fn prompt(&mut self, message: &str, echo: bool) -> Option<String> {
+            self.0.lock().unwrap().push(format!("prompt {message} {echo}"));
+            Some(format!("answer to {message}"))
+        }
+        fn info(&mut self, message: &str) {
+            self.0.lock().unwrap().push(format!("info {message}"));
+        }
+        fn error(&mut self, message: &str) {
+            self.0.lock().unwrap().push(format!("error {message}"));
+        }
+    }
+
+    fn conv_data(recorder: &Recorder) -> ConvData {
+        ConvData {
+            username: CString::new("user").unwrap(),
+            password: CString::new("secret").unwrap(),
+            password_used: false,
+            authtok_change: None,
+            conversation: Some(Box::new(recorder.clone())),
+        }
+    }
+
+    fn answer(data: &mut ConvData, style: PamMessageStyle, msg: &str) -> Option<String> {
+        data.answer(style, msg).unwrap().map(|a| a.into_string().unwrap())
+    }
+
+    #[test]
+    fn password_answers_first_hidden_prompt() {
+        let recorder = Recorder::default();
+        let mut data = conv_data(&recorder);
+        use PamMessageStyle::*;
+        assert_eq!(answer(&mut data, PROMPT_ECHO_OFF, "Password:").as_deref(), Some("secret"));
+        assert_eq!(answer(&mut data, PROMPT_ECHO_OFF, "Code:").as_deref(), Some("answer to Code:"));
+        assert_eq!(answer(&mut data, PROMPT_ECHO_ON, "Token:").as_deref(), Some("answer to Token:"));
+        assert_eq!(answer(&mut data, TEXT_INFO, "Hello"), None);
+        assert_eq!(answer(&mut data, ERROR_MSG, "Oops"), None);
+        assert_eq!(*recorder.0.lock().unwrap(), [
+            "prompt Code: false",
+            "prompt Token: true",
+            "info Hello",
+            "error Oops",
+        ]);
+    }
+
+    #[test]
+    fn authtok_change_answers_by_order() {
+        let recorder = Recorder::default();
+        let mut data = conv_data(&recorder);
+        data.password_used = true;
+        data.authtok_change = Some((
+            Some(CString::new("old").unwrap()),
+            CString::new("new").unwrap(),
+        ));
+        use PamMessageStyle::*;
+        // Whatever the wording
+        assert_eq!(answer(&mut data, PROMPT_ECHO_OFF, "Mot de passe :").as_deref(), Some("old"));
+        assert_eq!(answer(&mut data, PROMPT_ECHO_OFF, "Nouveau :").as_deref(), Some("new"));
+        assert_eq!(answer(&mut data, PROMPT_ECHO_OFF, "Retapez :").as_deref(), Some("new"));
+        // Echoed prompts still go to the conversation
+        assert_eq!(answer(&mut data, PROMPT_ECHO_ON, "Login:").as_deref(), Some("answer to Login:"));
+        assert_eq!(*recorder.0.lock().unwrap(), ["prompt Login: true"]);
+    }
+
+    #[test]
+    fn cancelled_prompt() {
+        let mut data = conv_data(&Recorder::default());
+        data.password_used = true;
+        data.conversation = None;
+        assert_eq!(
+            data.answer(PamMessageStyle::PROMPT_ECHO_ON, "Login:"),
+            Err(PamReturnCode::CONV_ERR)
+        );
+    }
+}
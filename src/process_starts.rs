@@ -1,69 +1,461 @@
+use crate::config::XServerConfig;
 use crate::pam_wrapper::Author;
+use crate::session_env::SessionEnv;
+use crate::sessions::{ Session, SessionKind };
+use crate::xauth::{ self, Cookie };
 
 use std::process;
 use std::sync::Mutex;
-use std::env;
-use std::path::Path;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread;
+use std::ffi::CString;
+use std::fs::{ File, OpenOptions };
+use std::io::{ self, Read };
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{ AsRawFd, FromRawFd };
+use std::os::unix::process::CommandExt;
+use std::path::{ Path, PathBuf };
 use std::time::{ Duration, Instant };
 
+use anyhow::{ anyhow, bail, Context };
 use users::os::unix::UserExt;
-use x11rb::protocol::randr::ConnectionExt;
-use x11rb::connection::Connection;
 
-const DISPLAY: &str = ":1";
-const VT: &str = "vt01";
+struct XServer {
+    process: process::Child,
+    display: String,
+    cookie: Cookie,
+    /// Where the server's authority file is, and the users' ones
+    auth_dir: PathBuf,
+}
 
-static X_SERVER: Mutex<Option<process::Child>> = Mutex::new(None);
+static X_SERVER: Mutex<Option<XServer>> = Mutex::new(None);
 static X_SERVER_TIMEOUT: Duration = Duration::from_millis(15000);
+/// Time given to the X server to exit after SIGTERM, before it is killed
+const X_SERVER_STOP_GRACE: Duration = Duration::from_secs(5);
+const X_SERVER_WATCH_INTERVAL: Duration = Duration::from_millis(500);
+/// Exit code of the greeter when its X server died, the supervisor restarts it
+pub const X_SERVER_CRASH_EXIT_CODE: i32 = 3;
+/// Exit code of the greeter when a Wayland session could not be started after
+/// the X server was stopped, the greeter can't show the error itself
+pub const SESSION_FAILURE_EXIT_CODE: i32 = 4;
+/// Once a session is started, the greeter waits for it to end even if the X
+/// server dies
+static SESSION_STARTED: AtomicBool = AtomicBool::new(false);
+const MAX_DISPLAY_NUMBER: u32 = 1000;
+
+// From linux/vt.h
+const VT_OPENQRY: libc::c_ulong = 0x5600;
+const VT_GETSTATE: libc::c_ulong = 0x5603;
+const VT_ACTIVATE: libc::c_ulong = 0x5606;
+const VT_WAITACTIVE: libc::c_ulong = 0x5607;
 
-pub fn start_x_server() {
+pub fn start_x_server(config: &XServerConfig, vt: u32) -> anyhow::Result<()> {
     let mut x_server = X_SERVER.lock().unwrap();
     if x_server.is_some() {
-        return;
+        return Ok(());
+    }
+    let display = if config.display == "auto" {
+        free_display().context("Could not find a free X display")?
     }
-    std::env::set_var("DISPLAY", DISPLAY);
-    let child = process::Command::new("/usr/lib/Xorg")
+    else {
+        config.display.clone()
+    };
+
+    let cookie = xauth::generate_cookie().context("Could not generate the X cookie")?;
+    xauth::write_server_file(&config.auth_path, display_number(&display), &cookie)
+        .context("Could not write the X server authority file")?;
+
+    // Xorg writes the display number on it once it accepts connections
+    let (ready_read, ready_write) = inheritable_pipe().context("Could not create the displayfd pipe")?;
+    let mut child = process::Command::new(&config.path)
         .arg("-nolisten").arg("tcp")
-        .arg(DISPLAY).arg(VT)
-        .arg("-keeptty") //.arg("-auth").arg("/usr/share/himmel_display_manager/xauthority")
-        .spawn().expect("Could not start the X server");
-    *x_server = Some(child);
+        .arg(&display).arg(format!("vt{vt}"))
+        .arg("-keeptty").arg("-auth").arg(&config.auth_path)
+        .arg("-displayfd").arg(ready_write.as_raw_fd().to_string())
+        .spawn()
+        .with_context(|| format!("Could not start the X server {}", config.path.display()))?;
+    // Only the X server must keep the writing end, so that EOF is seen if it exits
+    drop(ready_write);
 
-    let start = Instant::now();
+    let display = match wait_display_fd(ready_read, &mut child) {
+        Ok(display) => display,
+        Err(e) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!(e);
+        }
+    };
+
+    log::info!(phase = "x_server"; "X server ready on {display}");
+    std::env::set_var("DISPLAY", &display);
+    std::env::set_var("XAUTHORITY", &config.auth_path);
+    *x_server = Some(XServer {
+        process: child,
+        display,
+        cookie,
+        auth_dir: config.auth_path.parent().unwrap_or(Path::new("/")).to_path_buf(),
+    });
+    thread::spawn(watch_x_server);
+    Ok(())
+}
+
+/// Exits the greeter if the X server dies while it is shown
+fn watch_x_server() {
     loop {
-        match x11rb::connect(None) {
-            Ok(_) => break,
-            Err(e) => println!("Conn err: {e:?}"),
+        thread::sleep(X_SERVER_WATCH_INTERVAL);
+        let mut x_server = X_SERVER.lock().unwrap();
+        let status = match x_server.as_mut() {
+            // Stopped by stop_x_server
+            None => return,
+            Some(s) => match s.process.try_wait() {
+                Ok(Some(status)) => status,
+                _ => continue,
+            },
+        };
+        x_server.take();
+        drop(x_server);
+
+        log::error!(phase = "x_server"; "X server exited unexpectedly with {status}");
+        if SESSION_STARTED.load(Ordering::SeqCst) {
+            return;
         }
-        println!("{:?}", start.elapsed());
+        process::exit(X_SERVER_CRASH_EXIT_CODE);
+    }
+}
+
+/// Pipe whose reading end is closed on exec, but not the writing one, which
+/// is given to a child
+pub fn inheritable_pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    if unsafe { libc::fcntl(read.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((read, write))
+}
+
+/// Waits for the X server to write its display number followed by a newline,
+/// fails as soon as the server exits
+fn wait_display_fd(mut ready: File, child: &mut process::Child) -> Result<String, String> {
+    let start = Instant::now();
+    let mut received = Vec::new();
 
+    while !received.ends_with(b"\n") {
+        if let Ok(Some(status)) = child.try_wait() {
+            return Err(format!("X server exited during startup with {status}"));
+        }
         if start.elapsed() > X_SERVER_TIMEOUT {
-            panic!("X Server timeout");
+            return Err(String::from("X server timeout"));
         }
-        std::thread::sleep(Duration::from_millis(500));
-    };
+
+        let mut poll_fd = libc::pollfd {
+            fd: ready.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut poll_fd, 1, 100) } <= 0 {
+            continue;
+        }
+
+        let mut buffer = [0; 16];
+        match ready.read(&mut buffer) {
+            Ok(0) => {
+                // Closed without writing anything, the server is exiting
+                let status = child.wait().map_err(|e| e.to_string())?;
+                return Err(format!("X server exited during startup with {status}"));
+            }
+            Ok(n) => received.extend_from_slice(&buffer[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(format!("Could not read the displayfd pipe: {e}")),
+        }
+    }
+
+    let number = String::from_utf8_lossy(&received).trim().to_string();
+    Ok(format!(":{number}"))
 }
 
+/// Asks the X server to exit with SIGTERM, kills it if it is still running
+/// after a grace period
 pub fn stop_x_server() {
     let mut x_server_lock = X_SERVER.lock().unwrap();
     if let Some(mut s) = x_server_lock.take() {
-        s.kill().expect("Could not kill the X server");
+        terminate(&mut s.process, X_SERVER_STOP_GRACE);
+    }
+}
+
+fn terminate(child: &mut process::Child, grace: Duration) {
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+
+    let start = Instant::now();
+    while start.elapsed() < grace {
+        match child.try_wait() {
+            Ok(Some(_)) => return,
+            Ok(None) => thread::sleep(Duration::from_millis(100)),
+            Err(_) => break,
+        }
+    }
+
+    log::warn!(phase = "x_server"; "X server did not exit after SIGTERM, killing it");
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// ":1" -> "1"
+fn display_number(display: &str) -> &str {
+    display.trim_start_matches(':')
+}
+
+/// First display without a lock file nor a socket
+fn free_display() -> Option<String> {
+    (0..MAX_DISPLAY_NUMBER)
+        .find(|n| {
+            !Path::new(&format!("/tmp/.X{n}-lock")).exists()
+                && !Path::new(&format!("/tmp/.X11-unix/X{n}")).exists()
+        })
+        .map(|n| format!(":{n}"))
+}
+
+/// Display of the X server started by the greeter, if any
+pub fn display() -> Option<String> {
+    X_SERVER.lock().unwrap().as_ref().map(|x| x.display.clone())
+}
+
+/// Gives the user access to the running X server, returns the authority file
+/// to give as XAUTHORITY. Not in the user's home, which root may not be able
+/// to write (NFS with root_squash) and where the user could plant links
+fn authorize_user(user: &users::User) -> anyhow::Result<Option<PathBuf>> {
+    let x_server = X_SERVER.lock().unwrap();
+    let x_server = match &*x_server {
+        Some(x_server) => x_server,
+        // Started without our X server (debug or already running)
+        None => return Ok(None),
+    };
+
+    let path = x_server.auth_dir.join(format!("{}.Xauthority", user.uid()));
+    xauth::write_user_file(
+        &path,
+        display_number(&x_server.display),
+        &x_server.cookie,
+        user.uid(), user.primary_group_id(),
+    ).context("Could not write the user's Xauthority file")?;
+    Ok(Some(path))
+}
+
+/// Asks the kernel for the first VT nobody has opened
+pub fn allocate_vt() -> io::Result<u32> {
+    let console = File::open("/dev/tty0")?;
+    let mut vt: libc::c_int = -1;
+    if unsafe { libc::ioctl(console.as_raw_fd(), VT_OPENQRY, &mut vt) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if vt < 1 {
+        return Err(io::Error::other("No free VT"));
+    }
+    Ok(vt as u32)
+}
+
+/// The configured VT, or a free one
+pub fn greeter_vt(config: &XServerConfig) -> io::Result<u32> {
+    match config.fixed_vt() {
+        Some(vt) => Ok(vt),
+        None => allocate_vt(),
+    }
+}
+
+/// struct vt_stat of linux/vt.h
+#[repr(C)]
+#[derive(Default)]
+struct VtStat {
+    v_active: libc::c_ushort,
+    v_signal: libc::c_ushort,
+    v_state: libc::c_ushort,
+}
+
+/// Goes back to the greeter's VT once a session on another one ended, unless
+/// the user already switched elsewhere
+pub fn return_to_greeter_vt(session_vt: u32, greeter_vt: u32) -> io::Result<()> {
+    let console = File::open("/dev/tty0")?;
+    let mut state = VtStat::default();
+    if unsafe { libc::ioctl(console.as_raw_fd(), VT_GETSTATE, &mut state) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if u32::from(state.v_active) == session_vt {
+        activate_vt(greeter_vt)?;
+    }
+    Ok(())
+}
+
+/// Switches the display to the given VT and waits for the switch to be done
+fn activate_vt(vt: u32) -> io::Result<()> {
+    let console = File::open("/dev/tty0")?;
+    for request in [VT_ACTIVATE, VT_WAITACTIVE] {
+        if unsafe { libc::ioctl(console.as_raw_fd(), request, vt as libc::c_int) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
     }
+    Ok(())
 }
 
-pub fn start_session(mut author: Author, username: String) -> process::Child {
-    let user = users::get_user_by_name(&username).expect("Could not find user");
-    author.put_env("HOME", user.home_dir());
-    author.put_env("PWD", user.home_dir());
-    author.put_env("SHELL", user.shell());
-    author.put_env("USER", user.name());
-    author.put_env("LOGNAME", user.name());
-    author.put_env("PATH", "/usr/local/sbin:/usr/local/bin:/usr/bin:/bin");
-    author.put_env("MAIL", format!("/var/spool/mail/{}", user.name().to_string_lossy()));
-    author.put_env("XAUTHORITY", user.home_dir().join(".Xauthority"));
+/// Makes the command run as the given user, in a new session which takes its
+/// stdin as controlling terminal if it is a tty
+fn drop_privileges(
+    command: &mut process::Command,
+    user: &users::User,
+    controlling_tty: bool,
+) -> anyhow::Result<()> {
+    let uid = user.uid();
+    let gid = user.primary_group_id();
+    // Supplementary groups from the user database, resolved before the fork
+    // since NSS isn't usable in the child
+    let groups: Vec<libc::gid_t> = users::get_user_groups(user.name(), gid)
+        .context("Could not get the user's groups")?
+        .iter()
+        .map(|group| group.gid())
+        .collect();
+
+    let check = |r: libc::c_int| if r < 0 { Err(io::Error::last_os_error()) } else { Ok(()) };
+    // Safety: only async-signal-safe calls are made between fork and exec,
+    // nothing allocates
+    unsafe {
+        command.pre_exec(move || {
+            check(libc::setsid())?;
+            if controlling_tty {
+                check(libc::ioctl(libc::STDIN_FILENO, libc::TIOCSCTTY, 0))?;
+            }
+
+            check(libc::setgroups(groups.len(), groups.as_ptr()))?;
+            check(libc::setgid(gid))?;
+            check(libc::setuid(uid))?;
+            if libc::getuid() != uid || libc::geteuid() != uid {
+                // Not a custom error, which would allocate
+                return Err(io::Error::from_raw_os_error(libc::EPERM));
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// Sends the output of the command to the log file, keeping the previous one
+/// with a ".old" suffix. Done in the child once privileges are dropped, so
+/// that the user owns the file and no symlink of theirs can be followed with
+/// the greeter's privileges. The output is inherited if the log can't be opened
+fn redirect_output(command: &mut process::Command, log_path: &Path) -> anyhow::Result<()> {
+    let to_cstring = |path: &Path| CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("Invalid log path {}", log_path.display()));
+
+    // Parents first
+    let mut dirs = log_path.parent()
+        .map(|parent| parent.ancestors().map(to_cstring).collect::<Result<Vec<_>, _>>())
+        .transpose()?
+        .unwrap_or_default();
+    dirs.reverse();
+    let path = to_cstring(log_path)?;
+    let mut old_path = log_path.as_os_str().to_owned();
+    old_path.push(".old");
+    let old_path = to_cstring(Path::new(&old_path))?;
+
+    // Safety: only async-signal-safe calls are made between fork and exec,
+    // the paths are converted beforehand
+    unsafe {
+        command.pre_exec(move || {
+            for dir in &dirs {
+                libc::mkdir(dir.as_ptr(), 0o700);
+            }
+            libc::rename(path.as_ptr(), old_path.as_ptr());
+
+            let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+            let fd = libc::open(path.as_ptr(), flags, 0o600 as libc::c_uint);
+            if fd < 0 {
+                return Ok(());
+            }
+            libc::dup2(fd, libc::STDOUT_FILENO);
+            libc::dup2(fd, libc::STDERR_FILENO);
+            libc::close(fd);
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// Wayland sessions are started on the given VT, which must not be used by an
+/// X server. The author must be kept until the session exits, its PAM
+/// session is closed when dropped
+pub fn start_session(
+    author: &Author,
+    username: String,
+    session: &Session,
+    vt: u32,
+    log_path: &Path,
+) -> anyhow::Result<process::Child> {
+    let user = users::get_user_by_name(&username)
+        .ok_or_else(|| anyhow!("Could not find user {username}"))?;
+    let name = user.name().to_string_lossy();
+    let home = user.home_dir().to_string_lossy();
+
+    let mut env = SessionEnv::system(user.uid());
+    env
+        .set("HOME", home.as_ref())
+        .set("PWD", home.as_ref())
+        .set("SHELL", user.shell().to_string_lossy())
+        .set("USER", name.as_ref())
+        .set("LOGNAME", name.as_ref())
+        .set("MAIL", format!("/var/spool/mail/{name}"))
+        .set("XDG_SESSION_TYPE", session.kind.session_type());
+    if let Some(desktop) = session.session_desktop() {
+        env.set("XDG_SESSION_DESKTOP", desktop);
+    }
+    if let Some(desktop) = session.current_desktop() {
+        env.set("XDG_CURRENT_DESKTOP", desktop);
+    }
+
+    let mut command = process::Command::new(user.shell());
+    command
+        .arg("-c").arg(session.command())
+        .current_dir(user.home_dir());
+
+    let mut controlling_tty = false;
+    match session.kind {
+        SessionKind::Wayland => {
+            activate_vt(vt).with_context(|| format!("Could not switch to VT {vt}"))?;
+            let tty = OpenOptions::new()
+                .read(true).write(true)
+                .open(format!("/dev/tty{vt}"))
+                .context("Could not open the session tty")?;
+            command.stdin(tty);
+            controlling_tty = true;
+        }
+        SessionKind::X11 | SessionKind::Xinitrc => {
+            if let Some(display) = display() {
+                env.set("DISPLAY", display);
+            }
+            if let Some(auth_path) = authorize_user(&user)? {
+                env.set("XAUTHORITY", auth_path.to_string_lossy());
+            }
+        }
+    }
+
+    // The PAM modules have the last word (pam_env's PATH, pam_systemd's
+    // XDG_RUNTIME_DIR and XDG_SESSION_ID...)
+    env.extend(author.env_list());
+    let runtime_dir = format!("/run/user/{}", user.uid());
+    if Path::new(&runtime_dir).is_dir() {
+        env.set_default("XDG_RUNTIME_DIR", runtime_dir);
+    }
+    command.env_clear().envs(env.vars());
 
-    process::Command::new(user.shell())
-        .arg("-c").arg("/bin/bash --login .xinitrc")
-        .current_dir(user.home_dir())
-        .spawn().expect("Could not start session")
+    drop_privileges(&mut command, &user, controlling_tty)?;
+    // Registered after drop_privileges, so run as the user
+    redirect_output(&mut command, &user.home_dir().join(log_path))?;
+    log::info!(
+        username = username.as_str(), session = session.id.as_str(), phase = "session";
+        "Starting session {}", session.name
+    );
+    let child = command.spawn()
+        .with_context(|| format!("Could not start the session {}", session.name))?;
+    SESSION_STARTED.store(true, Ordering::SeqCst);
+    Ok(child)
 }
@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+const ETC_ENVIRONMENT: &str = "/etc/environment";
+const LOGIN_DEFS: &str = "/etc/login.defs";
+const DEFAULT_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+const DEFAULT_SUPATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Environment of a user's session, built from scratch so that nothing of the
+/// greeter's own environment is given to the session
+#[derive(Debug, Default, Clone)]
+pub struct SessionEnv {
+    vars: BTreeMap<String, String>,
+}
+
+impl SessionEnv {
+    /// PATH from /etc/login.defs and the variables of /etc/environment
+    pub fn system(uid: libc::uid_t) -> Self {
+        let mut env = Self::default();
+        env.set("PATH", login_defs_path(Path::new(LOGIN_DEFS), uid));
+        if let Ok(content) = fs::read_to_string(ETC_ENVIRONMENT) {
+            env.extend(parse_environment(&content));
+        }
+        env
+    }
+
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        self.vars.insert(key.into(), value.into());
+        self
+    }
+
+    /// Only sets the variable if nothing else did
+    pub fn set_default(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        self.vars.entry(key.into()).or_insert_with(|| value.into());
+        self
+    }
+
+    /// Adds the variables, replacing the ones already set
+    pub fn extend(&mut self, vars: impl IntoIterator<Item = (String, String)>) -> &mut Self {
+        self.vars.extend(vars);
+        self
+    }
+
+    /// To be given to [std::process::Command::envs] after env_clear
+    pub fn vars(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.vars.iter()
+    }
+}
+
+/// ENV_SUPATH for root, ENV_PATH for everyone else, either as "PATH=..." or
+/// directly the list of directories
+fn login_defs_path(path: &Path, uid: libc::uid_t) -> String {
+    let (key, default) = if uid == 0 {
+        ("ENV_SUPATH", DEFAULT_SUPATH)
+    }
+    else {
+        ("ENV_PATH", DEFAULT_PATH)
+    };
+
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(_) => return default.to_string(),
+    };
+    content.lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .find(|(k, _)| *k == key)
+        .map(|(_, value)| {
+            let value = value.trim();
+            value.strip_prefix("PATH=").unwrap_or(value).to_string()
+        })
+        .unwrap_or_else(|| default.to_string())
+}
+
+/// Reads KEY=value lines, as pam_env does for /etc/environment: comments,
+/// "export" prefixes and quotes around values are allowed, nothing is expanded
+fn parse_environment(content: &str) -> Vec<(String, String)> {
+    content.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.strip_prefix("export ").unwrap_or(line).trim_start())
+        .filter_map(|line| line.split_once('='))
+        .filter(|(key, _)| {
+            !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        })
+        .map(|(key, value)| {
+            let value = value.trim();
+            let unquoted = ['"', '\'']
+                .iter()
+                .find_map(|q| value.strip_prefix(*q)?.strip_suffix(*q))
+                .unwrap_or(value);
+            (key.to_string(), unquoted.to_string())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// PATH read from a login.defs with the given content
+    fn path_from(content: &str, uid: libc::uid_t) -> String {
+        let path = std::env::temp_dir()
+            .join(format!("himmel-login-defs-{}-{uid}-{}", std::process::id(), content.len()));
+        fs::write(&path, content).unwrap();
+        let result = login_defs_path(&path, uid);
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn login_defs() {
+        let content = "\
+# ENV_PATH PATH=/commented
+ENV_SUPATH\tPATH=/sbin:/bin
+ENV_PATH   PATH=/usr/bin:/bin
+";
+        assert_eq!(path_from(content, 1000), "/usr/bin:/bin");
+        assert_eq!(path_from(content, 0), "/sbin:/bin");
+        // Without the PATH= prefix
+        assert_eq!(path_from("ENV_PATH /opt/bin\n", 1000), "/opt/bin");
+        assert_eq!(path_from("ENV_SUPATH /sbin\n", 1000), DEFAULT_PATH);
+        assert_eq!(path_from("", 0), DEFAULT_SUPATH);
+        assert_eq!(login_defs_path(Path::new("/nonexistent/login.defs"), 1000), DEFAULT_PATH);
+    }
+
+    #[test]
+    fn environment() {
+        let content = "\
+# Comment
+LANG=en_US.UTF-8
+export EDITOR=vim
+QUOTED=\"a b\"
+SINGLE='c d'
+UNMATCHED=\"e
+EXPANDED=$HOME/bin
+not a variable
+BAD-KEY=1
+=empty key
+EMPTY=
+";
+        let vars = parse_environment(content);
+        let expected = [
+            ("LANG", "en_US.UTF-8"),
+            ("EDITOR", "vim"),
+            ("QUOTED", "a b"),
+            ("SINGLE", "c d"),
+            ("UNMATCHED", "\"e"),
+            ("EXPANDED", "$HOME/bin"),
+            ("EMPTY", ""),
+        ];
+        let expected: Vec<_> = expected.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert_eq!(vars, expected);
+    }
+
+    #[test]
+    fn defaults_dont_replace() {
+        let mut env = SessionEnv::default();
+        env.set("A", "1").set_default("A", "2").set_default("B", "3");
+        env.extend([(String::from("B"), String::from("4"))]);
+        let vars: Vec<_> = env.vars().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(vars, [("A", "1"), ("B", "4")]);
+    }
+}
@@ -0,0 +1,263 @@
+use crate::config::SessionsConfig;
+
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{ Path, PathBuf };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionKind {
+    X11,
+    Wayland,
+    /// The user's own ~/.xinitrc
+    Xinitrc,
+}
+
+impl SessionKind {
+    /// Value of XDG_SESSION_TYPE
+    pub fn session_type(self) -> &'static str {
+        match self {
+            SessionKind::X11 | SessionKind::Xinitrc => "x11",
+            SessionKind::Wayland => "wayland",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// Type and name of the desktop file without its extension, such as
+    /// "wayland/sway", since both directories may have a file of the same name
+    pub id: String,
+    pub name: String,
+    pub exec: String,
+    pub desktop_names: Vec<String>,
+    pub kind: SessionKind,
+}
+
+impl Session {
+    pub fn xinitrc() -> Self {
+        Self {
+            id: String::from("xinitrc"),
+            name: String::from("~/.xinitrc"),
+            exec: String::from(".xinitrc"),
+            desktop_names: Vec::new(),
+            kind: SessionKind::Xinitrc,
+        }
+    }
+
+    /// The command given to the user's shell to start the session
+    pub fn command(&self) -> String {
+        match self.kind {
+            SessionKind::Xinitrc => String::from("/bin/bash --login .xinitrc"),
+            SessionKind::X11 | SessionKind::Wayland => {
+                format!("exec /bin/bash --login -c {}", shell_quote(&self.exec))
+            }
+        }
+    }
+
+    /// Value of XDG_SESSION_DESKTOP, if any
+    pub fn session_desktop(&self) -> Option<&str> {
+        match self.kind {
+            SessionKind::Xinitrc => None,
+            SessionKind::X11 | SessionKind::Wayland => self.id.split_once('/').map(|(_, stem)| stem),
+        }
+    }
+
+    /// Value of XDG_CURRENT_DESKTOP, if any
+    pub fn current_desktop(&self) -> Option<String> {
+        if self.desktop_names.is_empty() {
+            None
+        }
+        else {
+            Some(self.desktop_names.join(":"))
+        }
+    }
+
+    /// Parses the content of a desktop entry file, returns None if the entry
+    /// is invalid or should not be shown
+    fn from_desktop_entry(stem: &str, content: &str, kind: SessionKind) -> Option<Self> {
+        let mut in_entry_group = false;
+        let mut name = None;
+        let mut exec = None;
+        let mut try_exec = None;
+        let mut desktop_names = Vec::new();
+
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('[') {
+                in_entry_group = line == "[Desktop Entry]";
+                continue;
+            }
+            if !in_entry_group {
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => continue,
+            };
+            match key {
+                "Name" => name = Some(value.to_string()),
+                "Exec" => exec = Some(strip_field_codes(value)),
+                "TryExec" => try_exec = Some(value.to_string()),
+                "DesktopNames" => desktop_names = value.split(';')
+                    .filter(|n| !n.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+                "Hidden" | "NoDisplay" if value == "true" => return None,
+                _ => (),
+            }
+        }
+
+        if let Some(try_exec) = try_exec {
+            if !is_executable_in_path(&try_exec) {
+                return None;
+            }
+        }
+
+        Some(Self {
+            name: name.unwrap_or_else(|| stem.to_string()),
+            exec: exec.filter(|e| !e.is_empty())?,
+            id: format!("{}/{}", kind.session_type(), stem),
+            desktop_names,
+            kind,
+        })
+    }
+}
+
+/// Lists the X and Wayland sessions sorted by name, the .xinitrc session
+/// is always the last one
+pub fn available_sessions(config: &SessionsConfig) -> Vec<Session> {
+    let mut sessions = Vec::new();
+    sessions.extend(read_sessions_dir(&config.xsessions_dir, SessionKind::X11));
+    sessions.extend(read_sessions_dir(&config.wayland_sessions_dir, SessionKind::Wayland));
+    sessions.sort_by(|a, b| a.name.cmp(&b.name));
+    sessions.push(Session::xinitrc());
+    sessions
+}
+
+fn read_sessions_dir(dir: &Path, kind: SessionKind) -> Vec<Session> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "desktop"))
+        .filter_map(|path| {
+            let stem = path.file_stem()?.to_str()?;
+            let content = fs::read_to_string(&path).ok()?;
+            Session::from_desktop_entry(stem, &content, kind)
+        })
+        .collect()
+}
+
+/// Removes the %f, %u... field codes of an Exec value
+fn strip_field_codes(exec: &str) -> String {
+    let mut result = String::with_capacity(exec.len());
+    let mut chars = exec.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        if let Some('%') = chars.next() {
+            result.push('%');
+        }
+    }
+    result.trim().to_string()
+}
+
+fn is_executable_in_path(program: &str) -> bool {
+    let is_executable = |path: &Path| path.metadata()
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false);
+
+    if program.contains('/') {
+        return is_executable(Path::new(program));
+    }
+
+    let path = env::var_os("PATH")
+        .unwrap_or_else(|| "/usr/local/bin:/usr/bin:/bin".into());
+    env::split_paths(&path)
+        .map(|dir: PathBuf| dir.join(program))
+        .any(|p| is_executable(&p))
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn desktop_entry() {
+        let content = "\
+# Comment
+[Desktop Entry]
+Name=Sway
+Exec=sway --unsupported-gpu %U
+DesktopNames=sway;wlroots;
+
+[Desktop Action Other]
+Name=Ignored
+";
+        let session = Session::from_desktop_entry("sway", content, SessionKind::Wayland).unwrap();
+        assert_eq!(session.id, "wayland/sway");
+        assert_eq!(session.name, "Sway");
+        assert_eq!(session.exec, "sway --unsupported-gpu");
+        assert_eq!(session.desktop_names, ["sway", "wlroots"]);
+        assert_eq!(session.session_desktop(), Some("sway"));
+        assert_eq!(session.current_desktop().as_deref(), Some("sway:wlroots"));
+    }
+
+    #[test]
+    fn same_file_in_both_directories() {
+        let content = "[Desktop Entry]\nExec=gnome-session\n";
+        let x11 = Session::from_desktop_entry("gnome", content, SessionKind::X11).unwrap();
+        let wayland = Session::from_desktop_entry("gnome", content, SessionKind::Wayland).unwrap();
+        assert_ne!(x11.id, wayland.id);
+        assert_eq!(x11.session_desktop(), wayland.session_desktop());
+        // Named after the file without a Name key
+        assert_eq!(x11.name, "gnome");
+    }
+
+    #[test]
+    fn invalid_desktop_entries() {
+        let parse = |content| Session::from_desktop_entry("test", content, SessionKind::X11);
+        assert!(parse("[Desktop Entry]\nName=No exec\n").is_none());
+        assert!(parse("[Desktop Entry]\nExec=\n").is_none());
+        assert!(parse("[Other Group]\nExec=wm\n").is_none());
+        assert!(parse("[Desktop Entry]\nExec=wm\nHidden=true\n").is_none());
+        assert!(parse("[Desktop Entry]\nExec=wm\nNoDisplay=true\n").is_none());
+        assert!(parse("[Desktop Entry]\nExec=wm\nTryExec=/nonexistent/wm\n").is_none());
+        assert!(parse("[Desktop Entry]\nExec=wm\nHidden=false\n").is_some());
+    }
+
+    #[test]
+    fn field_codes() {
+        assert_eq!(strip_field_codes("app %f %U"), "app");
+        assert_eq!(strip_field_codes("printf 100%%"), "printf 100%");
+        assert_eq!(strip_field_codes("app --opt=%k"), "app --opt=");
+        assert_eq!(strip_field_codes("app %"), "app");
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        let session = Session {
+            id: String::from("x11/wm"),
+            name: String::from("WM"),
+            exec: String::from("wm --title 'a b'"),
+            desktop_names: Vec::new(),
+            kind: SessionKind::X11,
+        };
+        assert_eq!(session.command(), r"exec /bin/bash --login -c 'wm --title '\''a b'\'''");
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+use std::fs::{ self, OpenOptions };
+use std::io::Write;
+use std::os::unix::fs::{ DirBuilderExt, OpenOptionsExt, PermissionsExt };
+use std::path::Path;
+
+use anyhow::Context;
+use serde::{ Deserialize, Serialize };
+
+/// What the greeter remembers across boots
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct State {
+    pub last_user: Option<String>,
+    /// Id of the last session chosen by each user
+    pub sessions: BTreeMap<String, String>,
+    /// Kept here so that restarting the greeter doesn't allow more attempts
+    pub failed_logins: BTreeMap<String, FailedLogins>,
+}
+
+/// Failed login attempts of a user since their last successful login
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FailedLogins {
+    pub count: u32,
+    /// Unix time before which no attempt is made
+    pub locked_until: Option<u64>,
+}
+
+impl State {
+    /// Missing or unreadable state is not an error, the greeter just starts
+    /// without preselected values
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(_) => return Self::default(),
+        };
+        match toml::from_str(&content) {
+            Ok(state) => state,
+            Err(e) => {
+                log::warn!("Ignoring invalid state file {}: {e}", path.display());
+                Self::default()
+            }
+        }
+    }
+
+    pub fn record_login(&mut self, username: &str, session_id: &str) {
+        self.last_user = Some(username.to_string());
+        self.sessions.insert(username.to_string(), session_id.to_string());
+    }
+
+    /// Atomically replaces the state file, which is only readable by its owner
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let content = toml::to_string(self)?;
+
+        if let Some(dir) = path.parent() {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)
+                .with_context(|| format!("Could not create {}", dir.display()))?;
+        }
+
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let mut file = OpenOptions::new()
+            .write(true).create(true).truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)
+            .with_context(|| format!("Could not create {}", Path::new(&tmp_path).display()))?;
+        // The mode is only applied when the file is created
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        drop(file);
+
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Could not replace {}", path.display()))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+use crate::process_starts::{ self, SESSION_FAILURE_EXIT_CODE, X_SERVER_CRASH_EXIT_CODE };
+
+use std::env;
+use std::fs::{ File, OpenOptions };
+use std::io::{ Read, Write };
+use std::os::unix::io::{ AsRawFd, FromRawFd, RawFd };
+use std::process;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{ Duration, Instant };
+
+/// Given to the child process to run the greeter itself
+pub const GREETER_ARG: &str = "--greeter";
+/// Given to the greeter until one started a login, so that logging out or a
+/// failed session shows the greeter instead of logging the autologin user in
+/// again
+pub const AUTOLOGIN_ARG: &str = "--autologin";
+/// Given to the greeter with the writing end of a pipe, written to once a
+/// login started
+const LOGIN_FD_ARG: &str = "--login-fd";
+
+/// The pipe of LOGIN_FD_ARG, in the greeter
+static LOGIN_PIPE: Mutex<Option<File>> = Mutex::new(None);
+
+/// A greeter exiting faster than this is considered as crashing
+const MIN_RUN_DURATION: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Crashes in a row after which an error is written on the console
+const MAX_QUICK_CRASHES: u32 = 5;
+
+/// Runs the greeter in a child process, starting a new one (with a fresh X
+/// server) every time the previous one exits after its session ended
+pub fn supervise() -> ! {
+    let exe = env::current_exe().expect("Could not find the current executable");
+    let args: Vec<_> = env::args_os().skip(1).collect();
+    let mut backoff = Duration::from_secs(1);
+    let mut quick_crashes = 0;
+    let mut autologin = true;
+
+    loop {
+        let start = Instant::now();
+        let login_pipe = process_starts::inheritable_pipe();
+        if let Err(e) = &login_pipe {
+            log::error!(phase = "supervisor"; "Could not create the login pipe: {e}");
+        }
+        let mut command = process::Command::new(&exe);
+        command
+            .args(&args)
+            .arg(GREETER_ARG)
+            .args(autologin.then_some(AUTOLOGIN_ARG));
+        if let Ok((_, write)) = &login_pipe {
+            command.arg(format!("{LOGIN_FD_ARG}={}", write.as_raw_fd()));
+        }
+        let status = command.status();
+        // Without the pipe, as if a login started so that autologin isn't retried
+        let login_started = login_pipe.map_or(true, |(read, write)| {
+            drop(write);
+            login_was_reported(read)
+        });
+
+        match &status {
+            Ok(status) if status.success() => (),
+            Ok(status) if status.code() == Some(X_SERVER_CRASH_EXIT_CODE) => {
+                log::error!(phase = "supervisor"; "The X server of the greeter died");
+            }
+            Ok(status) if status.code() == Some(SESSION_FAILURE_EXIT_CODE) => {
+                console_error("himmel: the session could not be started, see the system log");
+            }
+            Ok(status) => log::error!(phase = "supervisor"; "Greeter exited with {status}"),
+            Err(e) => log::error!(phase = "supervisor"; "Could not start the greeter: {e}"),
+        }
+
+        let crashed = !matches!(status, Ok(s) if s.success());
+        // However long the greeter ran, so that the message can be read
+        let session_failed = matches!(status, Ok(s) if s.code() == Some(SESSION_FAILURE_EXIT_CODE));
+        autologin &= crashed && !login_started && !session_failed;
+        if crashed && (start.elapsed() < MIN_RUN_DURATION || session_failed) {
+            quick_crashes += 1;
+            if quick_crashes >= MAX_QUICK_CRASHES {
+                console_error(&format!(
+                    "himmel: the greeter failed {quick_crashes} times in a row, retrying in {backoff:?}"
+                ));
+            }
+            log::info!(phase = "supervisor"; "Restarting the greeter in {backoff:?}");
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+        else {
+            quick_crashes = 0;
+            backoff = Duration::from_secs(1);
+        }
+    }
+}
+
+/// Reads the login pipe once the greeter exited, without waiting for EOF since
+/// its children may have inherited the writing end
+fn login_was_reported(mut read: File) -> bool {
+    unsafe { libc::fcntl(read.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) };
+    read.read(&mut [0]).is_ok_and(|n| n > 0)
+}
+
+/// Takes the pipe given by the supervisor, must be called in the greeter
+/// before it starts any process, which must not inherit it
+pub fn take_login_pipe() {
+    let fd = env::args_os().find_map(|arg| {
+        arg.to_str()?
+            .strip_prefix(LOGIN_FD_ARG)?
+            .strip_prefix('=')?
+            .parse::<RawFd>()
+            .ok()
+    });
+    if let Some(fd) = fd {
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            log::warn!(phase = "supervisor"; "Invalid login pipe {fd}");
+            return;
+        }
+        *LOGIN_PIPE.lock().unwrap() = Some(unsafe { File::from_raw_fd(fd) });
+    }
+}
+
+/// Tells the supervisor that a login started, autologin isn't retried if this
+/// greeter fails from now on
+pub fn report_login_started() {
+    if let Some(mut pipe) = LOGIN_PIPE.lock().unwrap().take() {
+        let _ = pipe.write_all(b"\n");
+    }
+}
+
+/// Writes the message on the current VT, which is back in text mode once the
+/// X server is gone
+fn console_error(message: &str) {
+    log::error!(phase = "supervisor"; "{message}");
+    if let Ok(mut console) = OpenOptions::new().write(true).open("/dev/tty0") {
+        let _ = writeln!(console, "\r\n{message}\r");
+    }
+}
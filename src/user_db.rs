@@ -0,0 +1,19 @@
+use crate::config::UsersConfig;
+
+use users::os::unix::UserExt;
+
+/// Returns the names of the accounts that should be listed on the greeter,
+/// sorted by uid
+pub fn human_users(config: &UsersConfig) -> Vec<String> {
+    // Safety: the user database is only enumerated from this thread
+    let mut users: Vec<_> = unsafe { users::all_users() }
+        .filter(|u| (config.min_uid..=config.max_uid).contains(&u.uid()))
+        .filter(|u| !config.hidden_shells.iter().any(|s| s == u.shell()))
+        .filter_map(|u| Some((u.uid(), u.name().to_str()?.to_string())))
+        .filter(|(_, name)| !config.hidden_users.contains(name))
+        .collect();
+    users.sort();
+    users.dedup();
+
+    users.into_iter().map(|(_, name)| name).collect()
+}
@@ -0,0 +1,182 @@
+use std::fs::{ self, File, OpenOptions };
+use std::io::{ self, Read, Write };
+use std::os::unix::fs::{ DirBuilderExt, OpenOptionsExt };
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+const FAMILY_WILD: u16 = 0xffff;
+const MIT_MAGIC_COOKIE: &[u8] = b"MIT-MAGIC-COOKIE-1";
+pub const COOKIE_LEN: usize = 16;
+
+pub type Cookie = [u8; COOKIE_LEN];
+
+/// One entry of an Xauthority file, as read and written by libXau
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    family: u16,
+    address: Vec<u8>,
+    number: Vec<u8>,
+    name: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl Entry {
+    /// Entry matching the given display on any host
+    fn cookie(display_number: &str, cookie: &Cookie) -> Self {
+        Self {
+            family: FAMILY_WILD,
+            address: Vec::new(),
+            number: display_number.as_bytes().to_vec(),
+            name: MIT_MAGIC_COOKIE.to_vec(),
+            data: cookie.to_vec(),
+        }
+    }
+
+    fn read_from(reader: &mut impl Read) -> io::Result<Option<Self>> {
+        let mut family = [0; 2];
+        match reader.read_exact(&mut family) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        fn read_field(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+            let mut len = [0; 2];
+            reader.read_exact(&mut len)?;
+            let mut field = vec![0; u16::from_be_bytes(len) as usize];
+            reader.read_exact(&mut field)?;
+            Ok(field)
+        }
+
+        Ok(Some(Self {
+            family: u16::from_be_bytes(family),
+            address: read_field(reader)?,
+            number: read_field(reader)?,
+            name: read_field(reader)?,
+            data: read_field(reader)?,
+        }))
+    }
+
+    fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&self.family.to_be_bytes())?;
+        for field in [&self.address, &self.number, &self.name, &self.data] {
+            writer.write_all(&(field.len() as u16).to_be_bytes())?;
+            writer.write_all(field)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn generate_cookie() -> io::Result<Cookie> {
+    let mut cookie = [0; COOKIE_LEN];
+    File::open("/dev/urandom")?.read_exact(&mut cookie)?;
+    Ok(cookie)
+}
+
+/// Writes the file given to the X server with -auth, only readable by root.
+/// Its directory can be crossed by the users to reach their own files
+pub fn write_server_file(path: &Path, display_number: &str, cookie: &Cookie) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::DirBuilder::new().recursive(true).mode(0o711).create(dir)?;
+    }
+    replace_file(path, &[Entry::cookie(display_number, cookie)], None)
+}
+
+/// Writes the file given to the user's session with XAUTHORITY, only readable
+/// by them. It is in the greeter's directory, nothing of the user's is read
+pub fn write_user_file(
+    path: &Path,
+    display_number: &str,
+    cookie: &Cookie,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+) -> io::Result<()> {
+    replace_file(path, &[Entry::cookie(display_number, cookie)], Some((uid, gid)))
+}
+
+/// Writes the entries to a new 0600 file and atomically moves it to the path
+fn replace_file(
+    path: &Path,
+    entries: &[Entry],
+    owner: Option<(libc::uid_t, libc::gid_t)>,
+) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".himmel-tmp");
+    let _ = fs::remove_file(&tmp_path);
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .mode(0o600)
+        .open(&tmp_path)?;
+
+    if let Some((uid, gid)) = owner {
+        if unsafe { libc::fchown(file.as_raw_fd(), uid, gid) } < 0 {
+            let e = io::Error::last_os_error();
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+    }
+
+    let mut buffer = Vec::new();
+    for entry in entries {
+        entry.write_to(&mut buffer)?;
+    }
+    file.write_all(&buffer)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let entries = [
+            Entry::cookie("0", &[7; COOKIE_LEN]),
+            Entry {
+                family: 256,
+                address: b"host".to_vec(),
+                number: b"12".to_vec(),
+                name: b"XDM-AUTHORIZATION-1".to_vec(),
+                data: vec![1, 2, 3],
+            },
+        ];
+        let mut buffer = Vec::new();
+        for entry in &entries {
+            entry.write_to(&mut buffer).unwrap();
+        }
+
+        let mut reader = buffer.as_slice();
+        let mut read = Vec::new();
+        while let Some(entry) = Entry::read_from(&mut reader).unwrap() {
+            read.push(entry);
+        }
+        assert_eq!(read, entries);
+    }
+
+    #[test]
+    fn libxau_format() {
+        let mut buffer = Vec::new();
+        Entry::cookie("1", &[0xab; COOKIE_LEN]).write_to(&mut buffer).unwrap();
+        let mut expected = vec![0xff, 0xff, 0, 0, 0, 1, b'1', 0, 18];
+        expected.extend_from_slice(MIT_MAGIC_COOKIE);
+        expected.extend_from_slice(&[0, 16]);
+        expected.extend_from_slice(&[0xab; COOKIE_LEN]);
+        assert_eq!(buffer, expected);
+    }
+
+    #[test]
+    fn truncated_entry() {
+        let mut buffer = Vec::new();
+        Entry::cookie("0", &[0; COOKIE_LEN]).write_to(&mut buffer).unwrap();
+        buffer.truncate(buffer.len() - 1);
+        assert!(Entry::read_from(&mut buffer.as_slice()).is_err());
+        // Nothing at all is the end of the file
+        assert!(Entry::read_from(&mut [].as_slice()).unwrap().is_none());
+    }
+}